@@ -1,31 +1,169 @@
 use std::collections::HashMap;
 
 use crate::lobby::Lobby;
+use crate::invite::{DEFAULT_INVITE_TTL, MAX_INVITE_TTL};
+use crate::messages::{
+    Admission, CheckAdmission, CreateRoom, CreateRoomError, DeleteRoom, GetRoomState, IssueInvite,
+    ListRooms, RoomCode, RoomSettings,
+};
+use crate::room_code::CodeOptions;
 use crate::ws::WsConn;
 use actix::Addr;
 use actix_web::{
-    get, post,
-    web::{Data, Path, Payload},
+    delete, get, post,
+    web::{Bytes, Data, Json, Path, Payload, Query},
     Error, HttpRequest, HttpResponse,
 };
 use actix_web_actors::ws;
-use rand::Rng;
+use serde::Deserialize;
 use std::sync::Mutex;
+use std::time::Duration;
 use uuid::Uuid;
 
+//固定房間名稱的長度限制
+const ROOM_NAME_MIN_LEN: usize = 3;
+const ROOM_NAME_MAX_LEN: usize = 32;
+//跟其他路徑撞名的不能拿來當房間名稱
+const RESERVED_ROOM_NAMES: [&str; 2] = ["rooms", "get_room_id"];
+
+//有保護的房間連線時帶 ?password=... 或 ?token=...
+#[derive(Deserialize)]
+pub struct Credentials {
+    password: Option<String>,
+    token: Option<String>,
+}
+
+//用 ?spectator=true 以旁觀者身分進房間，斷線後帶 ?reconnect=... 接回原本的身分，
+//建立房間的人帶 ?host_key=... 成為房主
+#[derive(Deserialize)]
+pub struct JoinOptions {
+    #[serde(default)]
+    spectator: bool,
+    reconnect: Option<String>,
+    host_key: Option<String>,
+}
+
+//刪除固定房間時帶 ?manage_key=...，建立房間時會拿到
+#[derive(Deserialize)]
+pub struct ManageKey {
+    manage_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PersistentRoomRequest {
+    name: String,
+    #[serde(default)]
+    settings: RoomSettings,
+}
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    password: Option<String>,
+    token: Option<String>,
+    host_key: Option<String>,
+    ttl_seconds: Option<u64>,
+}
+
+//固定房間名稱直接當網址用，只能用小寫英文、數字跟-，而且要英文開頭
+fn validate_room_name(name: &str) -> Result<(), String> {
+    if !(ROOM_NAME_MIN_LEN..=ROOM_NAME_MAX_LEN).contains(&name.len()) {
+        return Err(format!(
+            "Room names must be {} to {} characters long.",
+            ROOM_NAME_MIN_LEN, ROOM_NAME_MAX_LEN
+        ));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_lowercase())
+        || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err("Room names must start with a lowercase letter and contain only lowercase letters, digits and '-'.".to_string());
+    }
+    if RESERVED_ROOM_NAMES.contains(&name) {
+        return Err(format!("{} is a reserved name.", name));
+    }
+    Ok(())
+}
+
+//請Lobby挑號碼建立房間，房間建好之後才會登記號碼
+async fn open_room(
+    srv: &Addr<Lobby>,
+    code: RoomCode,
+    settings: RoomSettings,
+    persistent: bool,
+) -> Result<HttpResponse, Error> {
+    let result = srv
+        .send(CreateRoom {
+            code,
+            settings: settings.clone(),
+            persistent,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let created = match result {
+        Ok(created) => created,
+        Err(CreateRoomError::CodesExhausted) => {
+            println!("房間號碼已經用完");
+            return Ok(HttpResponse::ServiceUnavailable().body("No room codes are available right now, please try again later."));
+        }
+        Err(CreateRoomError::NameTaken(name)) => {
+            return Ok(HttpResponse::Conflict().body(format!("{} is already in use.", name)));
+        }
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "room_id": created.code,
+        "settings": settings,
+        "protected": settings.password.is_some() || settings.invite_only,
+        "persistent": persistent,
+        "invite_token": created.invite_token,
+        "manage_key": created.manage_key,
+        "host_key": created.host_key,
+    })))
+}
+
+fn admission_error(admission: Admission) -> HttpResponse {
+    match admission {
+        Admission::Allowed => HttpResponse::Ok().finish(),
+        Admission::RoomNotFound => HttpResponse::NotFound().finish(),
+        Admission::Unauthorized => HttpResponse::Unauthorized().body("This room requires a password or invite token."),
+        Admission::Forbidden => HttpResponse::Forbidden().body("Wrong password or invalid invite token."),
+    }
+}
+
 #[get("/{group_id}")]
 pub async fn start_connection(
     req: HttpRequest,
     stream: Payload,
     path: Path<String>,
+    credentials: Query<Credentials>,
+    join_options: Query<JoinOptions>,
     srv: Data<Addr<Lobby>>,
     room_id_map: Data<Mutex<HashMap<String, Uuid>>>,
 ) -> Result<HttpResponse, Error> {
     let group_id_str = path.into_inner();
-    let room_id_map = room_id_map.lock().unwrap();
-    if let Some(group_id) = room_id_map.get(&group_id_str) {
+    let group_id = room_id_map.lock().unwrap().get(&group_id_str).copied();
+    if let Some(group_id) = group_id {
         println!("group_id:{}",group_id);
-        let ws = WsConn::new(group_id.clone(), srv.get_ref().clone());
+        let credentials = credentials.into_inner();
+        let admission = srv
+            .send(CheckAdmission {
+                room_id: group_id,
+                password: credentials.password,
+                token: credentials.token,
+                host_key: join_options.host_key.clone(),
+                reconnect_token: join_options.reconnect.clone(),
+            })
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        if !matches!(admission, Admission::Allowed) {
+            return Ok(admission_error(admission));
+        }
+        let join_options = join_options.into_inner();
+        let ws = WsConn::new(
+            group_id,
+            srv.get_ref().clone(),
+            join_options.spectator,
+            join_options.reconnect,
+            join_options.host_key,
+        );
 
         let resp = ws::start(ws, &req, stream)?;
         Ok(resp)
@@ -34,23 +172,136 @@ pub async fn start_connection(
     }
 }
 
+//body可以不帶，全部用預設設定；號碼格式用query指定
 #[post("/get_room_id")]
-pub async fn get_room_id(room_id_map: Data<Mutex<HashMap<String, Uuid>>>) -> HttpResponse {
-    let mut rng = rand::thread_rng();
-    let mut new_id;
-
-    loop {
-        let random_number: u32 = rng.gen_range(10000..99999);
-        new_id = random_number.to_string();
-
-        let mut room_id_map = room_id_map.lock().unwrap();
-        if !room_id_map.contains_key(&new_id) {
-            let namespace = Uuid::NAMESPACE_DNS;
-            let new_uuid = Uuid::new_v5(&namespace, new_id.as_bytes());
-            room_id_map.insert(new_id.clone(), new_uuid);
-            break;
+pub async fn get_room_id(
+    code_options: Query<CodeOptions>,
+    body: Bytes,
+    srv: Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    //沒帶body才用預設設定，格式錯的body直接回400
+    let settings = if body.iter().all(u8::is_ascii_whitespace) {
+        RoomSettings::default()
+    } else {
+        match serde_json::from_slice::<RoomSettings>(&body) {
+            Ok(settings) => settings,
+            Err(e) => return Ok(HttpResponse::BadRequest().body(format!("Invalid room settings: {}", e))),
         }
+    };
+    if let Err(e) = settings.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    let code_options = code_options.into_inner();
+    if let Err(e) = code_options.validate() {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    open_room(&srv, RoomCode::Generate(code_options), settings, false).await
+}
+
+//建立固定房間，名稱就是房間號碼
+#[post("/rooms")]
+pub async fn create_persistent_room(
+    body: Json<PersistentRoomRequest>,
+    srv: Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let body = body.into_inner();
+    if let Err(e) = validate_room_name(&body.name).and_then(|_| body.settings.validate()) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    open_room(&srv, RoomCode::Named(body.name), body.settings, true).await
+}
+
+#[get("/rooms")]
+pub async fn list_persistent_rooms(srv: Data<Addr<Lobby>>) -> Result<HttpResponse, Error> {
+    let rooms = srv
+        .send(ListRooms)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(rooms))
+}
+
+//刪除固定房間，房間裡的人會被斷線
+#[delete("/rooms/{code}")]
+pub async fn delete_persistent_room(
+    path: Path<String>,
+    manage: Query<ManageKey>,
+    srv: Data<Addr<Lobby>>,
+    room_id_map: Data<Mutex<HashMap<String, Uuid>>>,
+) -> Result<HttpResponse, Error> {
+    let Some(room_id) = room_id_map.lock().unwrap().get(&path.into_inner()).copied() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let admission = srv
+        .send(DeleteRoom {
+            room_id,
+            manage_key: manage.into_inner().manage_key,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    match admission {
+        Admission::Allowed => Ok(HttpResponse::NoContent().finish()),
+        Admission::Unauthorized => Ok(HttpResponse::Unauthorized().body("Deleting this room requires its manage key.")),
+        Admission::Forbidden => Ok(HttpResponse::Forbidden().body("Wrong manage key.")),
+        admission => Ok(admission_error(admission)),
+    }
+}
+
+//用房間密碼、房主金鑰或已經有的邀請token產生新的邀請token
+#[post("/rooms/{code}/invites")]
+pub async fn create_invite(
+    path: Path<String>,
+    body: Json<InviteRequest>,
+    srv: Data<Addr<Lobby>>,
+    room_id_map: Data<Mutex<HashMap<String, Uuid>>>,
+) -> Result<HttpResponse, Error> {
+    let code = path.into_inner();
+    let Some(room_id) = room_id_map.lock().unwrap().get(&code).copied() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let body = body.into_inner();
+    let ttl = body
+        .ttl_seconds
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_INVITE_TTL)
+        .min(MAX_INVITE_TTL);
+    let result = srv
+        .send(IssueInvite {
+            room_id,
+            password: body.password,
+            token: body.token,
+            host_key: body.host_key,
+            ttl,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    match result {
+        Ok((token, ttl)) => Ok(HttpResponse::Ok().json(serde_json::json!({
+            "room_id": code,
+            "token": token,
+            "expires_in": ttl.as_secs(),
+        }))),
+        Err(admission) => Ok(admission_error(admission)),
+    }
+}
+
+//給看板或機器人用，不用開WebSocket也能看房間狀態
+#[get("/rooms/{code}")]
+pub async fn get_room_state(
+    path: Path<String>,
+    credentials: Query<Credentials>,
+    srv: Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let credentials = credentials.into_inner();
+    let result = srv
+        .send(GetRoomState {
+            code: path.into_inner(),
+            password: credentials.password,
+            token: credentials.token,
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    match result {
+        Ok(state) => Ok(HttpResponse::Ok().json(state)),
+        Err(admission) => Ok(admission_error(admission)),
     }
-    println!("加入房間，房間對應關係 {:?}",room_id_map);
-    HttpResponse::Ok().body(new_id)
 }
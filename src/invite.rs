@@ -0,0 +1,137 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+//邀請連結預設的有效時間
+pub const DEFAULT_INVITE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+//邀請連結最長的有效時間
+pub const MAX_INVITE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+//簽發跟驗證邀請token，密鑰每次啟動隨機產生，重開後舊token就失效(房間本來也不會保留)
+pub struct InviteSigner {
+    secret: [u8; 32],
+}
+
+impl InviteSigner {
+    pub fn new() -> Self {
+        InviteSigner {
+            secret: rand::thread_rng().gen(),
+        }
+    }
+
+    //token格式: {到期的unix秒數}.{hmac的hex}，簽名綁定房間的salt，房間重建後舊token不能用
+    pub fn sign(&self, room_salt: &Uuid, ttl: Duration) -> String {
+        let expires_at = unix_now().saturating_add(ttl.as_secs());
+        format!("{}.{}", expires_at, self.signature(room_salt, expires_at))
+    }
+
+    pub fn verify(&self, room_salt: &Uuid, token: &str) -> bool {
+        self.remaining(room_salt, token).is_some()
+    }
+
+    //token有效的話回傳還剩多久到期
+    pub fn remaining(&self, room_salt: &Uuid, token: &str) -> Option<Duration> {
+        let (expires_at, signature) = token.split_once('.')?;
+        let expires_at = expires_at.parse::<u64>().ok()?;
+        let signature = from_hex(signature).ok()?;
+        let now = unix_now();
+        if expires_at < now {
+            return None;
+        }
+        self.mac(room_salt, expires_at).verify_slice(&signature).ok()?;
+        Some(Duration::from_secs(expires_at - now))
+    }
+
+    fn mac(&self, room_salt: &Uuid, expires_at: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(room_salt.as_bytes());
+        mac.update(&expires_at.to_be_bytes());
+        mac
+    }
+
+    fn signature(&self, room_salt: &Uuid, expires_at: u64) -> String {
+        to_hex(&self.mac(room_salt, expires_at).finalize().into_bytes())
+    }
+}
+
+//房間密碼只存加鹽後的雜湊
+pub fn hash_password(room_salt: &Uuid, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(room_salt.as_bytes());
+    hasher.update(password.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or(0)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, ()> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| ()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_accepts_a_fresh_token_for_the_same_room() {
+        let signer = InviteSigner::new();
+        let room_salt = Uuid::new_v4();
+        let token = signer.sign(&room_salt, DEFAULT_INVITE_TTL);
+        assert!(signer.verify(&room_salt, &token));
+        assert!(signer.remaining(&room_salt, &token).is_some_and(|left| left <= DEFAULT_INVITE_TTL));
+        //換了房間(salt不同)就不能用
+        assert!(!signer.verify(&Uuid::new_v4(), &token));
+    }
+
+    #[test]
+    fn verify_rejects_expired_tokens() {
+        let signer = InviteSigner::new();
+        let room_salt = Uuid::new_v4();
+        let expires_at = unix_now() - 10;
+        let token = format!("{}.{}", expires_at, signer.signature(&room_salt, expires_at));
+        assert!(!signer.verify(&room_salt, &token));
+    }
+
+    #[test]
+    fn verify_rejects_tampered_tokens() {
+        let signer = InviteSigner::new();
+        let room_salt = Uuid::new_v4();
+        let token = signer.sign(&room_salt, DEFAULT_INVITE_TTL);
+        let (expires_at, signature) = token.split_once('.').unwrap();
+        //延長到期時間但沿用舊簽名
+        let extended = format!("{}.{}", expires_at.parse::<u64>().unwrap() + 1, signature);
+        assert!(!signer.verify(&room_salt, &extended));
+        assert!(!signer.verify(&room_salt, &format!("{}.{}", expires_at, "00".repeat(32))));
+        assert!(!signer.verify(&room_salt, "not-a-token"));
+        //別的伺服器(密鑰不同)簽的也不行
+        assert!(!InviteSigner::new().verify(&room_salt, &token));
+    }
+
+    #[test]
+    fn sign_saturates_huge_ttls() {
+        let signer = InviteSigner::new();
+        let room_salt = Uuid::new_v4();
+        let token = signer.sign(&room_salt, Duration::MAX);
+        assert!(token.starts_with(&format!("{}.", u64::MAX)));
+        assert!(signer.verify(&room_salt, &token));
+    }
+}
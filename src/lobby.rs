@@ -1,24 +1,82 @@
+use crate::invite::{hash_password, InviteSigner, DEFAULT_INVITE_TTL};
+use crate::nickname;
 use crate::messages::{
-    ClientActorMessage, Connect, Disconnect, JoinPayload, MessagePayload, Type, VoteMode, WsMessage,
+    Admission, CheckAdmission, ClientActorMessage, CloseConnection, Connect, CreateRoom, CreateRoomError,
+    CreatedRoom, DeleteRoom, Disconnect, GetRoomState, IssueInvite, JoinRoom, ListRooms,
+    JoinPayload, MessagePayload, MissingVotePolicy, Resumed, RoomSettings, Type, VoteMode, WsMessage,
+    RoomCode, DEFAULT_DISCONNECT_GRACE, MAX_VOTE_TIMEOUT, SAVE_MESSAGE_MAX_LEN,
 };
-use actix::prelude::{Actor, Context, Handler, Recipient};
+use crate::vote::{
+    approval_winner, borda_winner, instant_runoff, roulette_draw, validate_approval,
+    validate_ranking, validate_scores, Ballot, Bracket, Restaurant, Strike, Veto,
+};
+use actix::prelude::{Actor, AsyncContext, Context, Handler, Recipient};
 use actix_web::web::Data;
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use serde_json::to_string;
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 type Socket = Recipient<WsMessage>;
-const SAVE_MESSAGE_MAX_LEN: usize = 50;
+//檢查投票期限並廣播倒數的間隔
+const VOTE_TICK_INTERVAL: Duration = Duration::from_secs(1);
+//轉盤動畫每格的間隔
+const ROULETTE_SPIN_INTERVAL: Duration = Duration::from_millis(150);
+//多久檢查一次過期的房間
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+//建立後一直沒人進來的房間保留多久
+const UNJOINED_ROOM_TTL: Duration = Duration::from_secs(10 * 60);
+//房間多久沒有任何動靜就關掉
+const IDLE_ROOM_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+//斷線期間最多幫忙保留幾則廣播
+const MAX_MISSED_MESSAGES: usize = 200;
+//只有最新狀態有意義的廣播，斷線期間同一種只留最後一則
+const LATEST_ONLY_MESSAGES: [&str; 7] = [
+    "vote countdown",
+    "current vote count",
+    "roster",
+    "host",
+    "bracket",
+    "veto",
+    "roulette spin",
+];
 pub struct Lobby {
     sessions: HashMap<Uuid, Socket>, //使用者的uuid對應他的WsConn的ADDR
+    closers: HashMap<Uuid, Recipient<CloseConnection>>, //用來關閉使用者的連線
     rooms: HashMap<Uuid, RoomData>,  //房間的uuid 對應 每個房間使用者的uuid集合
     room_id_map: Data<Mutex<HashMap<String, Uuid>>>,
+    invites: InviteSigner,
+    //被重新連線取代的舊連線，之後送來的Disconnect要忽略
+    superseded: HashSet<Uuid>,
+}
+impl GamePhase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            GamePhase::Waiting => "waiting",
+            GamePhase::Selection => "selection",
+            GamePhase::Collecting => "collecting",
+            GamePhase::Voting => "voting",
+            GamePhase::Bracket => "bracket",
+            GamePhase::Striking => "striking",
+            GamePhase::Ending => "ending",
+        }
+    }
 }
 enum GamePhase {
     Waiting,
     Selection,
+    //候選池模式收集餐廳中
+    Collecting,
     Voting,
+    //淘汰賽進行中，一場一場投
+    Bracket,
+    //輪流否決中
+    Striking,
     Ending,
 }
 struct VoteData {
@@ -37,69 +95,574 @@ impl VoteData {
         }
     }
     
+    //已經投過另一邊的話會改票
     fn vote(&mut self, user_id: Uuid, is_agree: &str) -> bool {
-        if self.agree_list.contains(&user_id) || self.disagree_list.contains(&user_id) {
-            return false;
-        }
-    
-        match is_agree.to_lowercase().as_str() {
-            "true" => {
-                self.agree += 1;
-                self.agree_list.insert(user_id);
-            }
-            "false" => {
-                self.disagree += 1;
-                self.disagree_list.insert(user_id);
-            }
+        let is_agree = match is_agree.to_lowercase().as_str() {
+            "true" => true,
+            "false" => false,
             _ => {
                 println!("is agree: {}",is_agree);
                 return false;
             }
+        };
+        if (is_agree && self.agree_list.contains(&user_id))
+            || (!is_agree && self.disagree_list.contains(&user_id))
+        {
+            return false;
+        }
+        self.retract(user_id);
+
+        if is_agree {
+            self.agree += 1;
+            self.agree_list.insert(user_id);
+        } else {
+            self.disagree += 1;
+            self.disagree_list.insert(user_id);
         }
         true
     }
-    fn total_votes(&self) -> usize {
-        self.agree_list.len() + self.disagree_list.len()
+
+    fn retract(&mut self, user_id: Uuid) -> bool {
+        if self.agree_list.remove(&user_id) {
+            self.agree -= 1;
+            true
+        } else if self.disagree_list.remove(&user_id) {
+            self.disagree -= 1;
+            true
+        } else {
+            false
+        }
     }
 }
 
+//斷線的成員先保留位子，寬限時間內可以用reconnect token回來
+struct Away {
+    left_at: Instant,
+    //斷線期間錯過的廣播(type, json)，廣播時只拿得到&self所以用RefCell
+    missed: RefCell<VecDeque<(String, String)>>,
+}
+
+//每一輪決定的結果
+#[derive(Serialize)]
+struct RoundRecord {
+    round: usize,
+    restaurant_name: String,
+    vote_mode: Option<VoteMode>,
+    //結束時間(unix秒數)
+    finished_at: u64,
+}
+
 pub struct RoomData {
     users: HashSet<Uuid>,
+    //依加入順序，輪流否決時的順序
+    member_order: Vec<Uuid>,
     user_name_list:HashMap<Uuid,String>,
     data: VecDeque<(String, String)>,
     max_size: usize,
     vote_mode: Option<VoteMode>,
     game_phase: GamePhase,
+    //一次投一間的模式目前在投的餐廳
+    current_restaurant: Option<Restaurant>,
     current_restaurant_vote:VoteData,
+    candidates: Vec<Restaurant>,
+    ballots: HashMap<Uuid, Ballot>,
+    bracket: Option<Bracket>,
+    veto: Option<Veto>,
+    vote_timeout: Option<Duration>,
+    missing_vote: MissingVotePolicy,
+    vote_deadline: Option<Instant>,
+    //全員投完票後就鎖定並結算，關閉的話有期限的投票會等到時間到才結算，期間可以改票
+    lock_votes: bool,
+    //不記名投票，只公布票數
+    secret_ballot: bool,
+    max_members: Option<usize>,
+    //房主，預設是第一個進房間的人，離開時交給下一位
+    host: Option<Uuid>,
+    //被房主禁言的人
+    muted: HashSet<Uuid>,
+    //房間滿了之後進來的人在這裡排隊，有人離開就依序遞補
+    waitlist: VecDeque<Uuid>,
+    //旁觀者收得到所有廣播，但不算在users裡，不能投票也不能推薦餐廳
+    spectators: HashSet<Uuid>,
+    //加入時發給成員的reconnect token
+    reconnect_tokens: HashMap<Uuid, String>,
+    //斷線但還在寬限時間內的成員，仍然算在users裡
+    away: HashMap<Uuid, Away>,
+    disconnect_grace: Duration,
+    //簽邀請token跟密碼雜湊用，每個房間不同
+    room_salt: Uuid,
+    password_hash: Option<String>,
+    //固定房間的管理金鑰，跟密碼一樣只存雜湊
+    manage_key_hash: Option<String>,
+    //建立房間的人拿到的房主金鑰
+    host_key_hash: Option<String>,
+    //帶房主金鑰連進來的連線，加入時成為房主
+    creator: Option<Uuid>,
+    invite_only: bool,
+    //固定房間不會因為沒人或太久沒動靜被刪除
+    persistent: bool,
+    last_activity: Instant,
+    //已經結束的每一輪，開新一輪時保留
+    rounds: Vec<RoundRecord>,
+    //轉盤的seed在收第一個候選時就決定，先公布雜湊，轉完再公布seed讓大家驗證
+    roulette_seed: Option<u64>,
 }
 
 impl RoomData {
     pub fn new() -> Self {
         Self {
             users: HashSet::new(),
+            member_order: Vec::new(),
             user_name_list:HashMap::new(),
             data: VecDeque::with_capacity(SAVE_MESSAGE_MAX_LEN),
             max_size: SAVE_MESSAGE_MAX_LEN,
             vote_mode: None,
             game_phase: GamePhase::Waiting,
+            current_restaurant: None,
             current_restaurant_vote: VoteData::new(),
+            candidates: Vec::new(),
+            ballots: HashMap::new(),
+            bracket: None,
+            veto: None,
+            vote_timeout: None,
+            missing_vote: MissingVotePolicy::Abstain,
+            vote_deadline: None,
+            lock_votes: true,
+            secret_ballot: false,
+            max_members: None,
+            host: None,
+            muted: HashSet::new(),
+            waitlist: VecDeque::new(),
+            spectators: HashSet::new(),
+            reconnect_tokens: HashMap::new(),
+            away: HashMap::new(),
+            disconnect_grace: Duration::from_secs(DEFAULT_DISCONNECT_GRACE),
+            room_salt: Uuid::new_v4(),
+            password_hash: None,
+            manage_key_hash: None,
+            host_key_hash: None,
+            creator: None,
+            invite_only: false,
+            persistent: false,
+            last_activity: Instant::now(),
+            rounds: Vec::new(),
+            roulette_seed: None,
+        }
+    }
+
+    pub fn with_settings(settings: RoomSettings) -> Self {
+        let room_salt = Uuid::new_v4();
+        Self {
+            room_salt,
+            password_hash: settings
+                .password
+                .as_ref()
+                .map(|password| hash_password(&room_salt, password)),
+            invite_only: settings.invite_only,
+            data: VecDeque::with_capacity(settings.history_len),
+            max_size: settings.history_len,
+            vote_mode: settings.vote_mode,
+            vote_timeout: settings.vote_timeout.map(Duration::from_secs),
+            missing_vote: settings.missing_vote,
+            lock_votes: settings.lock_votes,
+            secret_ballot: settings.secret_ballot,
+            max_members: settings.max_members,
+            disconnect_grace: Duration::from_secs(settings.disconnect_grace),
+            ..Self::new()
+        }
+    }
+
+    pub fn settings(&self) -> RoomSettings {
+        RoomSettings {
+            vote_mode: self.vote_mode.clone(),
+            max_members: self.max_members,
+            vote_timeout: self.vote_timeout.map(|timeout| timeout.as_secs()),
+            missing_vote: self.missing_vote,
+            lock_votes: self.lock_votes,
+            history_len: self.max_size,
+            secret_ballot: self.secret_ballot,
+            password: None,
+            invite_only: self.invite_only,
+            disconnect_grace: self.disconnect_grace.as_secs(),
         }
     }
 
+    //房間名單：每個人的名字、身分、在線狀態跟這一輪投票了沒，還沒報名字的不列出
+    fn roster(&self) -> Vec<serde_json::Value> {
+        let mut spectators: Vec<(&Uuid, &String)> = self
+            .spectators
+            .iter()
+            .filter_map(|user_id| self.user_name_list.get(user_id).map(|name| (user_id, name)))
+            .collect();
+        spectators.sort_by_key(|(_, name)| *name);
+        let members = self
+            .member_order
+            .iter()
+            .filter_map(|user_id| self.user_name_list.get(user_id).map(|name| (user_id, name)))
+            .map(|(user_id, name)| {
+                serde_json::json!({
+                    "name": name,
+                    "role": if self.host == Some(*user_id) { "host" } else { "member" },
+                    "status": if self.away.contains_key(user_id) { "away" } else { "online" },
+                    "voted": self.has_voted(user_id),
+                })
+            });
+        members
+            .chain(spectators.into_iter().map(|(_, name)| {
+                serde_json::json!({
+                    "name": name,
+                    "role": "spectator",
+                    "status": "online",
+                    "voted": false,
+                })
+            }))
+            .collect()
+    }
+
+    //給GET /rooms/{code}用的房間狀態
+    fn state(&self, code: &str) -> serde_json::Value {
+        let members: Vec<&String> = self
+            .member_order
+            .iter()
+            .filter_map(|user_id| self.user_name_list.get(user_id))
+            .collect();
+        let away: Vec<&String> = self
+            .member_order
+            .iter()
+            .filter(|user_id| self.away.contains_key(user_id))
+            .filter_map(|user_id| self.user_name_list.get(user_id))
+            .collect();
+        let spectators: Vec<&String> = self
+            .spectators
+            .iter()
+            .filter_map(|user_id| self.user_name_list.get(user_id))
+            .collect();
+        let vote = if !self.is_voting() {
+            serde_json::Value::Null
+        } else if self.uses_candidate_pool() {
+            serde_json::json!({ "voted": self.ballots.len(), "total": self.users.len() })
+        } else if self.secret_ballot {
+            let vote_data = &self.current_restaurant_vote;
+            serde_json::json!({ "voted": vote_data.agree + vote_data.disagree, "total": self.users.len() })
+        } else {
+            serde_json::json!({
+                "agree": self.current_restaurant_vote.agree,
+                "disagree": self.current_restaurant_vote.disagree,
+                "total": self.users.len(),
+            })
+        };
+        let current_candidate = match (&self.bracket, &self.veto, &self.current_restaurant) {
+            (Some(bracket), _, _) => serde_json::json!(bracket.current_match().map(|(first, second)| vec![first, second])),
+            (_, Some(veto), _) => serde_json::json!(veto.remaining),
+            (_, _, Some(restaurant)) if !self.uses_candidate_pool() && self.is_voting() => {
+                serde_json::json!({ "restaurant_name": restaurant.name, "remark": restaurant.remark })
+            }
+            _ => serde_json::Value::Null,
+        };
+        let candidates: Vec<serde_json::Value> = self
+            .candidates
+            .iter()
+            .map(|c| serde_json::json!({ "restaurant_name": c.name, "remark": c.remark }))
+            .collect();
+        let history: Vec<serde_json::Value> = self
+            .data
+            .iter()
+            .map(|(name, value)| {
+                match serde_json::from_str::<serde_json::Value>(value)
+                    .ok()
+                    .filter(|info| info["restaurant_name"].is_string())
+                {
+                    Some(info) => serde_json::json!({
+                        "name": name,
+                        "restaurant_name": info["restaurant_name"],
+                        "remark": info["remark"],
+                    }),
+                    None => serde_json::json!({ "name": name, "message": value }),
+                }
+            })
+            .collect();
+        serde_json::json!({
+            "room_id": code,
+            "phase": self.game_phase.as_str(),
+            "settings": self.settings(),
+            "host": self.host.and_then(|host| self.user_name_list.get(&host)),
+            "members": members,
+            "away": away,
+            "spectators": spectators,
+            "roster": self.roster(),
+            "waitlist_length": self.waitlist.len(),
+            "current_candidate": current_candidate,
+            "candidates": candidates,
+            "vote": vote,
+            "seed_hash": self.roulette_seed_hash(),
+            "history": history,
+            "rounds": self.rounds,
+        })
+    }
+
     pub fn insert_data(&mut self, new_data: (String, String)) {
+        if self.max_size == 0 {
+            return;
+        }
         if self.data.len() >= self.max_size {
             self.data.pop_front();
         }
         self.data.push_back(new_data);
     }
+
+    //決定好餐廳，記錄這一輪的結果
+    fn finish_round(&mut self, restaurant_name: String) {
+        self.game_phase = GamePhase::Ending;
+        self.vote_deadline = None;
+        self.rounds.push(RoundRecord {
+            round: self.rounds.len() + 1,
+            restaurant_name,
+            vote_mode: self.vote_mode.clone(),
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0),
+        });
+    }
+
+    //開新的一輪，成員、設定跟聊天紀錄都保留
+    fn reset_round(&mut self) {
+        self.game_phase = GamePhase::Waiting;
+        self.current_restaurant = None;
+        self.current_restaurant_vote = VoteData::new();
+        self.candidates.clear();
+        self.ballots.clear();
+        self.bracket = None;
+        self.veto = None;
+        self.vote_deadline = None;
+        self.roulette_seed = None;
+    }
+
+    //沒人的房間過了UNJOINED_ROOM_TTL、或太久沒動靜就算過期
+    fn is_expired(&self, now: Instant) -> bool {
+        if self.persistent {
+            return false;
+        }
+        let idle = now.duration_since(self.last_activity);
+        let unjoined = self.users.is_empty() && self.waitlist.is_empty() && self.spectators.is_empty();
+        (unjoined && idle > UNJOINED_ROOM_TTL) || idle > IDLE_ROOM_TTL
+    }
+
+    //沒人也沒人旁觀的一般房間就可以刪掉，固定房間保留設定跟紀錄
+    fn is_abandoned(&self) -> bool {
+        !self.persistent && self.users.is_empty() && self.spectators.is_empty()
+    }
+
+    fn find_reconnect(&self, token: &str) -> Option<Uuid> {
+        self.reconnect_tokens
+            .iter()
+            .find(|(_, reconnect_token)| *reconnect_token == token)
+            .map(|(user_id, _)| *user_id)
+    }
+
+    //幫斷線的人記下錯過的廣播，太多的話丟掉最舊的
+    fn record_missed(&self, message_type: &str, json_message: &str) {
+        let latest_only = LATEST_ONLY_MESSAGES.contains(&message_type);
+        for away in self.away.values() {
+            let mut missed = away.missed.borrow_mut();
+            if latest_only {
+                missed.retain(|(missed_type, _)| missed_type != message_type);
+            }
+            if missed.len() >= MAX_MISSED_MESSAGES {
+                missed.pop_front();
+            }
+            missed.push_back((message_type.to_string(), json_message.to_string()));
+        }
+    }
+
+    fn record_missed_message(&self, message: &str) {
+        let payload = MessagePayload {
+            r#type: "message".to_string(),
+            message: message.to_string(),
+        };
+        if let Ok(json_message) = to_string(&payload) {
+            self.record_missed(&payload.r#type, &json_message);
+        }
+    }
+
+
+    //所有會收到廣播的人，連線中的成員加上旁觀者
+    fn audience(&self) -> impl Iterator<Item = &Uuid> {
+        self.users
+            .iter()
+            .filter(|user_id| !self.away.contains_key(user_id))
+            .chain(self.spectators.iter())
+    }
+
+    fn find_member(&self, name: &str) -> Option<Uuid> {
+        let key = nickname::key(name.trim());
+        self.member_order
+            .iter()
+            .find(|user_id| self.user_name_list.get(user_id).is_some_and(|n| nickname::key(n) == key))
+            .copied()
+    }
+
+    //最早加入、還連著的人
+    fn first_present_member(&self) -> Option<Uuid> {
+        self.member_order
+            .iter()
+            .find(|id| !self.away.contains_key(id))
+            .copied()
+    }
+
+    //正式成員、候補跟旁觀者
+    fn is_participant(&self, user_id: &Uuid) -> bool {
+        self.users.contains(user_id) || self.waitlist.contains(user_id) || self.spectators.contains(user_id)
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_members
+            .is_some_and(|max_members| self.users.len() >= max_members)
+    }
+
+    fn is_protected(&self) -> bool {
+        self.password_hash.is_some() || self.invite_only
+    }
+
+    //密碼或邀請token其中一個對就可以
+    fn check_credentials(&self, invites: &InviteSigner, password: Option<&str>, token: Option<&str>) -> Admission {
+        if !self.is_protected() {
+            return Admission::Allowed;
+        }
+        if password.is_none() && token.is_none() {
+            return Admission::Unauthorized;
+        }
+        let password_ok = self.password_matches(password);
+        let token_ok = token.is_some_and(|token| invites.verify(&self.room_salt, token));
+        if password_ok || token_ok {
+            Admission::Allowed
+        } else {
+            Admission::Forbidden
+        }
+    }
+
+    fn is_host_key(&self, host_key: &str) -> bool {
+        self.host_key_hash
+            .as_ref()
+            .is_some_and(|host_key_hash| *host_key_hash == hash_password(&self.room_salt, host_key))
+    }
+
+    fn password_matches(&self, password: Option<&str>) -> bool {
+        match (&self.password_hash, password) {
+            (Some(password_hash), Some(password)) => *password_hash == hash_password(&self.room_salt, password),
+            _ => false,
+        }
+    }
+
+    fn uses_candidate_pool(&self) -> bool {
+        self.vote_mode
+            .as_ref()
+            .is_some_and(|mode| mode.uses_candidate_pool())
+    }
+
+    fn has_voted(&self, user_id: &Uuid) -> bool {
+        if self.uses_candidate_pool() {
+            self.ballots.contains_key(user_id)
+        } else {
+            self.current_restaurant_vote.agree_list.contains(user_id)
+                || self.current_restaurant_vote.disagree_list.contains(user_id)
+        }
+    }
+
+    //進入投票階段，有設定時間的話開始倒數
+    fn begin_voting(&mut self) {
+        self.game_phase = GamePhase::Voting;
+        self.start_vote_timer();
+    }
+
+    fn start_vote_timer(&mut self) {
+        self.vote_deadline = self
+            .vote_timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+    }
+
+    fn is_voting(&self) -> bool {
+        matches!(
+            self.game_phase,
+            GamePhase::Voting | GamePhase::Bracket | GamePhase::Striking
+        )
+    }
+
+    //時間到了，依房間設定補上沒投票的人(候選池模式一律當作棄權)
+    fn apply_missing_votes(&mut self) {
+        if self.uses_candidate_pool() {
+            return;
+        }
+        let is_agree = match self.missing_vote {
+            MissingVotePolicy::Abstain => return,
+            MissingVotePolicy::Agree => "true",
+            MissingVotePolicy::Disagree => "false",
+        };
+        let missing: Vec<Uuid> = self
+            .users
+            .iter()
+            .filter(|user_id| !self.has_voted(user_id))
+            .copied()
+            .collect();
+        for user_id in missing {
+            self.current_restaurant_vote.vote(user_id, is_agree);
+        }
+    }
+
+    //房間內每個人都投完票了
+    fn vote_complete(&self) -> bool {
+        !self.users.is_empty() && self.users.iter().all(|user_id| self.has_voted(user_id))
+    }
+
+    //轉盤開轉前公布的seed雜湊，結果出來後可以拿公布的seed對照
+    fn roulette_seed_hash(&self) -> Option<String> {
+        self.roulette_seed
+            .map(|seed| format!("{:x}", Sha256::digest(seed.to_be_bytes())))
+    }
+
+    //不用等到期限就可以結算，不鎖票的話要等到期限或房主強制結算
+    fn ready_to_close(&self) -> bool {
+        self.vote_complete() && self.lock_votes
+    }
 }
 
 impl Lobby {
     pub fn new(room_id_map: Data<Mutex<HashMap<String, Uuid>>>) -> Self {
         Self {
             sessions: HashMap::new(),
+            closers: HashMap::new(),
             rooms: HashMap::new(),
             room_id_map,
+            invites: InviteSigner::new(),
+            superseded: HashSet::new(),
+        }
+    }
+    //刪除房間，還連著的人(包含候補)會被斷線
+    fn remove_room(&mut self, room_id: &Uuid, reason: &str) {
+        self.remove_room_id(room_id);
+        if let Some(room_data) = self.rooms.remove(room_id) {
+            for user_id in room_data.audience().chain(room_data.waitlist.iter()) {
+                self.sessions.remove(user_id);
+                if let Some(closer) = self.closers.remove(user_id) {
+                    closer.do_send(CloseConnection {
+                        reason: reason.to_string(),
+                    });
+                }
+            }
+        }
+        println!("刪除房間，房間對應關係 {:?}", self.room_id_map);
+    }
+    fn sweep_expired_rooms(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Uuid> = self
+            .rooms
+            .iter()
+            .filter(|(_, room_data)| room_data.is_expired(now))
+            .map(|(room_id, _)| *room_id)
+            .collect();
+        for room_id in expired {
+            println!("房間過期: {}", room_id);
+            self.remove_room(&room_id, "Room expired.");
         }
     }
     fn remove_room_id(&self, room_id: &Uuid) {
@@ -128,7 +691,7 @@ impl Lobby {
 
             match to_string(&payload) {
                 Ok(json_message) => {
-                    let _ = socket_recipient.do_send(WsMessage(json_message));
+                    socket_recipient.do_send(WsMessage(json_message));
                 }
                 Err(e) => {
                     println!("Failed to serialize message payload: {}", e);
@@ -138,19 +701,24 @@ impl Lobby {
             println!("Attempting to send message but couldn't find user id.");
         }
     }
+    //只送給一個人的事件
+    fn send_payload(&self, payload: &serde_json::Value, id_to: &Uuid) {
+        if let Some(socket_recipient) = self.sessions.get(id_to) {
+            socket_recipient.do_send(WsMessage(payload.to_string()));
+        }
+    }
     fn send_join_message(&self, message: &str, room_id: &Uuid) {
         if let Some(room_data) = self.rooms.get_key_value(room_id) {
-            let users = &room_data.1.users;
             let payload = JoinPayload {
                 r#type: "join".to_string(),
                 message: message.to_string(),
-                length: users.len(),
+                length: room_data.1.users.len(),
             };
-            for user in users {
+            for user in room_data.1.audience() {
                 if let Some(socket_recipient) = self.sessions.get(user) {
                     match to_string(&payload) {
                         Ok(json_message) => {
-                            let _ = socket_recipient.do_send(WsMessage(json_message));
+                            socket_recipient.do_send(WsMessage(json_message));
                         }
                         Err(e) => {
                             println!("Failed to serialize message payload: {}", e);
@@ -158,223 +726,1195 @@ impl Lobby {
                     }
                 }
             }
+            if let Ok(json_message) = to_string(&payload) {
+                room_data.1.record_missed(&payload.r#type, &json_message);
+            }
         } else {
             println!("Attempting to send message but couldn't find user id.");
         }
     }
-    fn send_selection_restaurant(&self, room_id: &Uuid, restaurant_name: &str, remark: &str) {
-        if let Some(room_data) = self.rooms.get_key_value(room_id) {
-            let users = &room_data.1.users;
-
-            let custom_payload = serde_json::json!({
-                "type": "add restaurant",
-                "restaurant_name": restaurant_name,
-                "remark": remark,
-            });
-
-            match to_string(&custom_payload) {
-                Ok(json_message) => {
-                    for user in users {
-                        if let Some(socket_recipient) = self.sessions.get(user) {
-                            let _ = socket_recipient.do_send(WsMessage(json_message.clone()));
-                        }
-                    }
+    //帶reconnect token回來，接回原本的身分、名字跟投票，再補送斷線期間的廣播
+    fn resume_session(
+        &mut self,
+        room_id: &Uuid,
+        token: &str,
+        addr: Socket,
+        close_addr: Recipient<CloseConnection>,
+    ) -> Option<Resumed> {
+        let room_data = self.rooms.get_mut(room_id)?;
+        let user_id = room_data.find_reconnect(token)?;
+        //舊的連線還沒斷(例如換網路)，直接換成新的連線
+        if room_data.users.contains(&user_id) && !room_data.away.contains_key(&user_id) {
+            let name = room_data.user_name_list.get(&user_id).cloned().unwrap_or_default();
+            if let Some(closer) = self.closers.remove(&user_id) {
+                closer.do_send(CloseConnection {
+                    reason: "Resumed on another connection.".to_string(),
+                });
+                self.superseded.insert(user_id);
+            }
+            self.sessions.insert(user_id, addr);
+            self.closers.insert(user_id, close_addr);
+            return Some(Resumed { id: user_id, name });
+        }
+        //位子還保留著，回到房間
+        let away = room_data.away.remove(&user_id)?;
+        let name = room_data.user_name_list.get(&user_id).cloned().unwrap_or_default();
+        println!("{} 重新連線", name);
+        self.sessions.insert(user_id, addr);
+        self.closers.insert(user_id, close_addr);
+        for (_, json_message) in away.missed.into_inner() {
+            if let Some(socket_recipient) = self.sessions.get(&user_id) {
+                socket_recipient.do_send(WsMessage(json_message));
+            }
+        }
+        self.broadcast(room_id, &serde_json::json!({ "type": "back", "name": name }));
+        self.send_roster(room_id);
+        Some(Resumed { id: user_id, name })
+    }
+    //斷線後保留位子，寬限時間到還沒回來才真的移除
+    fn mark_away(&mut self, room_id: &Uuid, user_id: &Uuid, ctx: &mut Context<Self>) {
+        let room_data = self.rooms.get(room_id).unwrap();
+        let grace = room_data.disconnect_grace;
+        let name = room_data.user_name_list.get(user_id).cloned();
+        self.broadcast(
+            room_id,
+            &serde_json::json!({
+                "type": "away",
+                "name": name,
+                "grace_seconds": grace.as_secs(),
+            }),
+        );
+        let left_at = Instant::now();
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        room_data.away.insert(
+            *user_id,
+            Away {
+                left_at,
+                missed: RefCell::new(VecDeque::new()),
+            },
+        );
+        if room_data.veto.as_ref().is_some_and(|veto| veto.current() == Some(*user_id)) {
+            self.skip_veto_turn(room_id);
+        }
+        //房主斷線就先交給還連著的人，沒有其他人在就等他回來
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        if room_data.host == Some(*user_id) {
+            if let Some(host) = room_data.first_present_member() {
+                room_data.host = Some(host);
+                self.send_host(room_id);
+            }
+        }
+        self.send_roster(room_id);
+        let (room_id, user_id) = (*room_id, *user_id);
+        ctx.run_later(grace, move |act, _| {
+            //中間回來又斷線的話left_at會不一樣，交給新的計時
+            let still_away = act
+                .rooms
+                .get(&room_id)
+                .and_then(|room_data| room_data.away.get(&user_id))
+                .is_some_and(|away| away.left_at == left_at);
+            if still_away {
+                act.remove_member(&room_id, &user_id);
+            }
+        });
+    }
+    //把成員移出房間，有需要就遞補候補、換房主、重新檢查投票
+    fn remove_member(&mut self, room_id: &Uuid, user_id: &Uuid) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        room_data.away.remove(user_id);
+        room_data.reconnect_tokens.remove(user_id);
+        room_data.muted.remove(user_id);
+        room_data.users.remove(user_id);
+        room_data.member_order.retain(|id| id != user_id);
+        //還沒報名字就斷線的不用通知
+        if let Some(name) = room_data.user_name_list.remove(user_id) {
+            let message = format!("{} disconnected.", name);
+            let room_data = self.rooms.get(room_id).unwrap();
+            room_data
+                .audience()
+                .for_each(|client| self.send_message(&message, client));
+            room_data.record_missed_message(&message);
+        }
+        let room_data = self.rooms.get(room_id).unwrap();
+        if room_data.veto.as_ref().is_some_and(|veto| veto.current() == Some(*user_id)) {
+            self.skip_veto_turn(room_id);
+        }
+        self.promote_from_waitlist(room_id);
+        //房主離開就交給最早加入、還連著的人，大家都斷線的話就交給最早加入的人
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        if room_data.host == Some(*user_id) {
+            room_data.host = room_data
+                .first_present_member()
+                .or_else(|| room_data.member_order.first().copied());
+            self.send_host(room_id);
+        }
+        //最後一個人離開就移除房間(還有人在旁觀就先留著)
+        let room_data = self.rooms.get(room_id).unwrap();
+        if room_data.is_abandoned() {
+            self.remove_room(room_id, "Room closed.");
+            return;
+        }
+        self.send_roster(room_id);
+        //少了一個人，剩下的人可能都已經投完了
+        if room_data.is_voting() && room_data.ready_to_close() {
+            self.close_vote(room_id);
+        }
+    }
+    //正式加入房間，並傳輸歷史紀錄給新進來的人知道
+    fn admit_member(&mut self, room_id: &Uuid, user_id: Uuid) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        room_data.users.insert(user_id);
+        room_data.member_order.push(user_id);
+        //建立房間的人回來就把房主交還給他
+        if room_data.creator == Some(user_id) {
+            room_data.host = Some(user_id);
+        }
+        //否決進行中才加入的人排到最後
+        if let Some(veto) = room_data.veto.as_mut() {
+            veto.order.push(user_id);
+        }
+    }
+    fn send_history(&self, room_id: &Uuid, user_id: &Uuid) {
+        // self.send_message(&format!("your id is {}", user_id), &user_id);
+        //紀錄裡推薦的餐廳是json，其他是聊天訊息
+        for (keys, value) in self.rooms.get(room_id).unwrap().data.iter() {
+            let restaurant_info = serde_json::from_str::<serde_json::Value>(value).ok();
+            match restaurant_info.as_ref().and_then(|info| info["restaurant_name"].as_str()) {
+                Some(restaurant_name) => {
+                    let remark = restaurant_info.as_ref().unwrap()["remark"].as_str().unwrap_or("");
+                    self.send_message(&format!("{} suggest[restaurant: {}, remark: {}]", keys, restaurant_name, remark), user_id);
                 }
-                Err(e) => {
-                    println!("Failed to serialize custom payload to JSON: {}", e);
+                None => self.send_message(&format!("{} say: {}", keys, value), user_id),
+            }
+        }
+        // self.send_message("--------------history~--------------", &user_id);
+        //轉盤已經公布過seed雜湊的話，後來加入的人也要拿到
+        if let Some(seed_hash) = self.rooms.get(room_id).unwrap().roulette_seed_hash() {
+            self.send_payload(
+                &serde_json::json!({ "type": "roulette commitment", "seed_hash": seed_hash }),
+                user_id,
+            );
+        }
+    }
+    //有空位就讓候補名單最前面的人進來
+    //斷線後可以用這個token接回原本的身分
+    fn send_reconnect_token(&mut self, room_id: &Uuid, user_id: &Uuid) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        let token = room_data
+            .reconnect_tokens
+            .entry(*user_id)
+            .or_insert_with(|| Uuid::new_v4().simple().to_string())
+            .clone();
+        let payload = serde_json::json!({
+            "type": "reconnect token",
+            "token": token,
+            "grace_seconds": room_data.disconnect_grace.as_secs(),
+        });
+        self.send_payload(&payload, user_id);
+    }
+    fn promote_from_waitlist(&mut self, room_id: &Uuid) {
+        let mut promoted = false;
+        while let Some(room_data) = self.rooms.get_mut(room_id) {
+            if room_data.is_full() {
+                break;
+            }
+            let Some(user_id) = room_data.waitlist.pop_front() else {
+                break;
+            };
+            promoted = true;
+            self.admit_member(room_id, user_id);
+            self.send_history(room_id, &user_id);
+            if let Some(socket_recipient) = self.sessions.get(&user_id) {
+                socket_recipient.do_send(WsMessage(serde_json::json!({ "type": "waitlist promoted" }).to_string()));
+            }
+            //還沒報名字的人等報名字時再發token
+            let room_data = self.rooms.get(room_id).unwrap();
+            if let Some(name) = room_data.user_name_list.get(&user_id) {
+                self.send_join_message(&format!("{} join the room.", name), room_id);
+                if room_data.host == Some(user_id) {
+                    self.send_host(room_id);
                 }
+                self.send_reconnect_token(room_id, &user_id);
             }
-        } else {
-            println!("Attempting to send message but couldn't find room id.");
+        }
+        if promoted {
+            self.send_waitlist_positions(room_id);
+            self.send_roster(room_id);
         }
     }
-    fn send_vote_result(&self, room_id: &Uuid, result: &str, reject_list: HashSet<Uuid>) {
+    //告訴每個候補的人自己排第幾位
+    fn send_waitlist_positions(&self, room_id: &Uuid) {
         if let Some(room_data) = self.rooms.get(room_id) {
-            let users = &room_data.users;
-            let reject_names: Vec<String> = reject_list
-                .iter()
-                .filter_map(|user_id| room_data.user_name_list.get(user_id).cloned())
-                .collect();
-            let payload = serde_json::json!({
-                "type": "vote result",
-                "result": result,
-                "reject_list": reject_names,
-            });
-
-            match to_string(&payload) {
+            for (index, user_id) in room_data.waitlist.iter().enumerate() {
+                if let Some(socket_recipient) = self.sessions.get(user_id) {
+                    let payload = serde_json::json!({
+                        "type": "waitlist",
+                        "position": index + 1,
+                        "length": room_data.waitlist.len(),
+                    });
+                    socket_recipient.do_send(WsMessage(payload.to_string()));
+                }
+            }
+        }
+    }
+    //成員或投票狀態有變就廣播最新的名單
+    fn send_roster(&self, room_id: &Uuid) {
+        if let Some(room_data) = self.rooms.get(room_id) {
+            self.broadcast(
+                room_id,
+                &serde_json::json!({ "type": "roster", "members": room_data.roster() }),
+            );
+        }
+    }
+    fn send_host(&self, room_id: &Uuid) {
+        if let Some(room_data) = self.rooms.get(room_id) {
+            let name = room_data
+                .host
+                .and_then(|host| room_data.user_name_list.get(&host));
+            self.broadcast(room_id, &serde_json::json!({ "type": "host", "name": name }));
+        }
+    }
+    //只有房主可以做，不是房主就回錯誤訊息
+    fn require_host(&self, room_id: &Uuid, user_id: &Uuid) -> bool {
+        let is_host = self
+            .rooms
+            .get(room_id)
+            .is_some_and(|room_data| room_data.host == Some(*user_id));
+        if !is_host {
+            self.send_message("Only the host can do that.", user_id);
+        }
+        is_host
+    }
+    //廣播給房間內所有人
+    fn broadcast(&self, room_id: &Uuid, payload: &serde_json::Value) {
+        if let Some(room_data) = self.rooms.get(room_id) {
+            match to_string(payload) {
                 Ok(json_message) => {
-                    for user_id in users {
+                    for user_id in room_data.audience() {
                         if let Some(socket_recipient) = self.sessions.get(user_id) {
-                            let _ = socket_recipient.do_send(WsMessage(json_message.clone()));
+                            socket_recipient.do_send(WsMessage(json_message.clone()));
                         }
                     }
+                    let message_type = payload["type"].as_str().unwrap_or_default();
+                    room_data.record_missed(message_type, &json_message);
                 }
                 Err(e) => {
-                    println!("Failed to serialize vote result payload to JSON: {}", e);
+                    println!("Failed to serialize broadcast payload to JSON: {}", e);
                 }
             }
+        } else {
+            println!("Attempting to broadcast but couldn't find room id.");
+        }
+    }
+    fn send_selection_restaurant(&self, room_id: &Uuid, restaurant_name: &str, remark: &str) {
+        self.broadcast(
+            room_id,
+            &serde_json::json!({
+                "type": "add restaurant",
+                "restaurant_name": restaurant_name,
+                "remark": remark,
+            }),
+        );
+    }
+    fn send_vote_result(&self, room_id: &Uuid, result: &str, reject_list: HashSet<Uuid>) {
+        if let Some(room_data) = self.rooms.get(room_id) {
+            let vote_data = &room_data.current_restaurant_vote;
+            let mut payload = if room_data.secret_ballot {
+                serde_json::json!({
+                    "type": "vote result",
+                    "result": result,
+                    "agree": vote_data.agree,
+                    "disagree": vote_data.disagree,
+                })
+            } else {
+                let reject_names: Vec<String> = reject_list
+                    .iter()
+                    .filter_map(|user_id| room_data.user_name_list.get(user_id).cloned())
+                    .collect();
+                serde_json::json!({
+                    "type": "vote result",
+                    "result": result,
+                    "reject_list": reject_names,
+                })
+            };
+            if let Some(VoteMode::Threshold { percent, quorum }) = &room_data.vote_mode {
+                payload["threshold"] = serde_json::json!({
+                    "percent": percent,
+                    "quorum": quorum,
+                    "agree": vote_data.agree,
+                    "total": vote_data.agree + vote_data.disagree,
+                });
+            }
+            self.broadcast(room_id, &payload);
         } else {
             println!("Attempting to send vote result but couldn't find room id.");
         }
     }
     fn send_current_vote_count(&self, room_id: &Uuid) {
         if let Some(room_data) = self.rooms.get(room_id) {
-            let vote_data = &room_data.current_restaurant_vote;
-            let payload = serde_json::json!({
-                "type": "current vote count",
-                "agree": vote_data.agree,
-                "disagree": vote_data.disagree,
-            });
+            let payload = if room_data.uses_candidate_pool() {
+                serde_json::json!({
+                    "type": "current vote count",
+                    "voted": room_data.ballots.len(),
+                    "total": room_data.users.len(),
+                })
+            } else if room_data.secret_ballot {
+                //不記名的話公布結果前只說幾個人投了，不然跟名單上的投票狀態一比就知道誰投反對
+                let vote_data = &room_data.current_restaurant_vote;
+                serde_json::json!({
+                    "type": "current vote count",
+                    "voted": vote_data.agree + vote_data.disagree,
+                    "total": room_data.users.len(),
+                })
+            } else {
+                let vote_data = &room_data.current_restaurant_vote;
+                serde_json::json!({
+                    "type": "current vote count",
+                    "agree": vote_data.agree,
+                    "disagree": vote_data.disagree,
+                })
+            };
+            self.broadcast(room_id, &payload);
+        } else {
+            println!("Attempting to send current vote count but couldn't find room id.");
+        }
+    }
+    //關閉候選池，開始一起投票
+    fn start_pool_vote(&mut self, room_id: &Uuid, user_id: &Uuid, ctx: &mut Context<Self>) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        if !room_data.uses_candidate_pool() || !matches!(room_data.game_phase, GamePhase::Collecting) {
+            return;
+        }
+        if room_data.candidates.len() < 2 {
+            self.send_message("At least two restaurants are needed to start a vote.", user_id);
+            return;
+        }
+        if let Some(VoteMode::Roulette { weighted }) = room_data.vote_mode {
+            self.spin_roulette(room_id, weighted, ctx);
+            return;
+        }
+        room_data.ballots.clear();
+        room_data.begin_voting();
+        match room_data.vote_mode {
+            Some(VoteMode::Bracket) => {
+                room_data.game_phase = GamePhase::Bracket;
+                room_data.bracket = Some(Bracket::new(&room_data.candidates));
+            }
+            Some(VoteMode::Veto) => {
+                room_data.game_phase = GamePhase::Striking;
+                let mut veto = Veto::new(&room_data.candidates, room_data.member_order.clone());
+                //第一位不在的話從下一位還在的人開始
+                let (users, away) = (&room_data.users, &room_data.away);
+                let is_present = |id: &Uuid| users.contains(id) && !away.contains_key(id);
+                if !veto.current().is_some_and(|id| is_present(&id)) {
+                    veto.advance(is_present);
+                }
+                room_data.veto = Some(veto);
+            }
+            _ => (),
+        }
+        let candidates: Vec<serde_json::Value> = room_data
+            .candidates
+            .iter()
+            .map(|c| serde_json::json!({ "restaurant_name": c.name, "remark": c.remark }))
+            .collect();
+        self.broadcast(
+            room_id,
+            &serde_json::json!({
+                "type": "vote start",
+                "candidates": candidates,
+            }),
+        );
+        self.send_bracket_state(room_id);
+        self.send_veto_state(room_id, None);
+        self.send_roster(room_id);
+    }
+    //廣播還剩哪些餐廳、剛剛誰劃掉哪間以及輪到誰
+    fn send_veto_state(&self, room_id: &Uuid, strike: Option<&Strike>) {
+        if let Some(room_data) = self.rooms.get(room_id) {
+            if let Some(veto) = &room_data.veto {
+                let next = veto
+                    .current()
+                    .and_then(|user_id| room_data.user_name_list.get(&user_id));
+                self.broadcast(
+                    room_id,
+                    &serde_json::json!({
+                        "type": "veto",
+                        "remaining": veto.remaining,
+                        "strike": strike,
+                        "next": next,
+                    }),
+                );
+            }
+        }
+    }
+    fn strike_restaurant(&mut self, room_id: &Uuid, user_id: &Uuid, user_name: String, restaurant_name: String) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        let Some(veto) = room_data.veto.as_mut() else {
+            return;
+        };
+        if veto.current() != Some(*user_id) {
+            self.send_message("It's not your turn to strike.", user_id);
+            return;
+        }
+        if !veto.remaining.contains(&restaurant_name) {
+            self.send_message("That restaurant isn't in the remaining list.", user_id);
+            return;
+        }
+        veto.remaining.retain(|name| *name != restaurant_name);
+        let strike = Strike {
+            user_name,
+            restaurant_name,
+        };
+        veto.strikes.push(strike.clone());
+        let (users, away) = (&room_data.users, &room_data.away);
+        veto.advance(|id| users.contains(id) && !away.contains_key(id));
+        let winner = (veto.remaining.len() == 1).then(|| veto.remaining[0].clone());
+        room_data.start_vote_timer();
+        self.send_veto_state(room_id, Some(&strike));
+        if let Some(winner) = winner {
+            let room_data = self.rooms.get_mut(room_id).unwrap();
+            let strikes = room_data.veto.take().map(|veto| veto.strikes);
+            room_data.finish_round(winner.clone());
+            self.broadcast(
+                room_id,
+                &serde_json::json!({
+                    "type": "vote result",
+                    "result": "pass",
+                    "restaurant_name": winner,
+                    "strikes": strikes,
+                }),
+            );
+        }
+    }
+    //輪到的人離開或超時就跳過
+    fn skip_veto_turn(&mut self, room_id: &Uuid) {
+        let Some(room_data) = self.rooms.get_mut(room_id) else {
+            return;
+        };
+        if let Some(veto) = room_data.veto.as_mut() {
+            let (users, away) = (&room_data.users, &room_data.away);
+            veto.advance(|id| users.contains(id) && !away.contains_key(id));
+            room_data.start_vote_timer();
+            self.send_veto_state(room_id, None);
+        }
+    }
+    //廣播淘汰賽目前的對戰跟已經打完的場次
+    fn send_bracket_state(&self, room_id: &Uuid) {
+        if let Some(bracket) = self.rooms.get(room_id).and_then(|room_data| room_data.bracket.as_ref()) {
+            self.broadcast(
+                room_id,
+                &serde_json::json!({
+                    "type": "bracket",
+                    "round": bracket.round,
+                    "current_match": bracket.current_match().map(|(first, second)| vec![first, second]),
+                    "matches": bracket.matches,
+                    "champion": bracket.champion,
+                }),
+            );
+        }
+    }
+    //轉盤這一輪的seed，產生後只先公布雜湊
+    fn commit_roulette_seed(&mut self, room_id: &Uuid) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        if !matches!(room_data.vote_mode, Some(VoteMode::Roulette { .. })) || room_data.roulette_seed.is_some() {
+            return;
+        }
+        room_data.roulette_seed = Some(rand::thread_rng().gen());
+        let seed_hash = room_data.roulette_seed_hash();
+        self.broadcast(
+            room_id,
+            &serde_json::json!({
+                "type": "roulette commitment",
+                "seed_hash": seed_hash,
+            }),
+        );
+    }
+    //轉盤不用投票，依序送出轉動的每一格讓前端做動畫，最後公布結果跟seed
+    fn spin_roulette(&mut self, room_id: &Uuid, weighted: bool, ctx: &mut Context<Self>) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        let seed = room_data
+            .roulette_seed
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        room_data.game_phase = GamePhase::Voting;
+        let result = roulette_draw(&room_data.candidates, weighted, seed);
+        println!("轉盤 seed: {}, 抽中: {}", seed, result.winner);
 
-            match to_string(&payload) {
-                Ok(json_message) => {
-                    for user_id in &room_data.users {
-                        if let Some(socket_recipient) = self.sessions.get(user_id) {
-                            let _ = socket_recipient.do_send(WsMessage(json_message.clone()));
-                        }
-                    }
+        let spin_count = result.spins.len();
+        for (step, restaurant_name) in result.spins.into_iter().enumerate() {
+            let room_id = *room_id;
+            ctx.run_later(ROULETTE_SPIN_INTERVAL * step as u32, move |act, _| {
+                act.broadcast(
+                    &room_id,
+                    &serde_json::json!({
+                        "type": "roulette spin",
+                        "step": step + 1,
+                        "total_steps": spin_count,
+                        "restaurant_name": restaurant_name,
+                    }),
+                );
+            });
+        }
+        let room_id = *room_id;
+        ctx.run_later(ROULETTE_SPIN_INTERVAL * spin_count as u32, move |act, _| {
+            if let Some(room_data) = act.rooms.get_mut(&room_id) {
+                room_data.finish_round(result.winner.clone());
+                act.broadcast(
+                    &room_id,
+                    &serde_json::json!({
+                        "type": "vote result",
+                        "result": "pass",
+                        "restaurant_name": result.winner,
+                        "seed": seed,
+                        "weights": result.weights,
+                    }),
+                );
+            }
+        });
+    }
+    fn cast_ballot(&mut self, room_id: &Uuid, user_id: Uuid, ballot: Ballot) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        if !room_data.is_voting() {
+            return;
+        }
+        //再投一次就是改票
+        room_data.ballots.insert(user_id, ballot);
+        self.send_current_vote_count(room_id);
+        self.send_roster(room_id);
+        if self.rooms.get(room_id).unwrap().ready_to_close() {
+            self.close_vote(room_id);
+        }
+    }
+    //每秒檢查一次有期限的投票，廣播剩餘時間，時間到就用目前的票結算
+    fn tick_vote_deadlines(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (room_id, room_data) in &self.rooms {
+            if let Some(deadline) = room_data.vote_deadline {
+                let remaining = deadline.saturating_duration_since(now);
+                self.broadcast(
+                    room_id,
+                    &serde_json::json!({
+                        "type": "vote countdown",
+                        "remaining_seconds": remaining.as_secs_f64().ceil() as u64,
+                    }),
+                );
+                if remaining.is_zero() {
+                    expired.push(*room_id);
                 }
-                Err(e) => {
-                    println!("Failed to serialize current vote count payload to JSON: {}", e);
+            }
+        }
+        for room_id in expired {
+            println!("投票時間到: {}", room_id);
+            self.rooms.get_mut(&room_id).unwrap().apply_missing_votes();
+            self.close_vote(&room_id);
+        }
+    }
+    //結算投票結果
+    fn close_vote(&mut self, room_id: &Uuid) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        room_data.vote_deadline = None;
+        let room_data = self.rooms.get(room_id).unwrap();
+        let vote_data = &room_data.current_restaurant_vote;
+        match &room_data.vote_mode {
+            //時間到但沒有人投票就不算通過
+            Some(VoteMode::MajorityDecision) => {
+                let passed = vote_data.agree > 0 && vote_data.agree >= vote_data.disagree;
+                self.finish_single_vote(room_id, passed);
+            }
+            Some(VoteMode::ConsensusDecision) => {
+                let passed = vote_data.agree > 0 && vote_data.disagree == 0;
+                self.finish_single_vote(room_id, passed);
+            }
+            Some(VoteMode::Threshold { percent, quorum }) => {
+                let total = vote_data.agree + vote_data.disagree;
+                let passed = total > 0
+                    && total >= quorum.unwrap_or(0)
+                    && vote_data.agree * 100 >= *percent as usize * total;
+                self.finish_single_vote(room_id, passed);
+            }
+            Some(VoteMode::RankedChoice) => {
+                let candidates: Vec<String> = room_data.candidates.iter().map(|c| c.name.clone()).collect();
+                let rankings: Vec<&Vec<String>> = room_data
+                    .ballots
+                    .values()
+                    .filter_map(|ballot| match ballot {
+                        Ballot::Ranking(ranking) => Some(ranking),
+                        _ => None,
+                    })
+                    .collect();
+                let result = instant_runoff(&candidates, &rankings);
+                self.broadcast(
+                    room_id,
+                    &serde_json::json!({
+                        "type": "vote result",
+                        "result": if result.winner.is_some() { "pass" } else { "failed" },
+                        "restaurant_name": result.winner,
+                        "rounds": result.rounds,
+                    }),
+                );
+                self.finish_pool_vote(room_id, result.winner);
+            }
+            Some(VoteMode::Approval) => {
+                let candidates: Vec<String> = room_data.candidates.iter().map(|c| c.name.clone()).collect();
+                let approvals: Vec<&HashSet<String>> = room_data
+                    .ballots
+                    .values()
+                    .filter_map(|ballot| match ballot {
+                        Ballot::Approval(approvals) => Some(approvals),
+                        _ => None,
+                    })
+                    .collect();
+                let (winner, tally) = approval_winner(&candidates, &approvals);
+                self.broadcast(
+                    room_id,
+                    &serde_json::json!({
+                        "type": "vote result",
+                        "result": if winner.is_some() { "pass" } else { "failed" },
+                        "restaurant_name": winner,
+                        "tally": tally,
+                    }),
+                );
+                self.finish_pool_vote(room_id, winner);
+            }
+            Some(VoteMode::BordaCount) => {
+                let candidates: Vec<String> = room_data.candidates.iter().map(|c| c.name.clone()).collect();
+                let ballots: Vec<&HashMap<String, usize>> = room_data
+                    .ballots
+                    .values()
+                    .filter_map(|ballot| match ballot {
+                        Ballot::Scores(scores) => Some(scores),
+                        _ => None,
+                    })
+                    .collect();
+                let (winner, table) = borda_winner(&candidates, &ballots);
+                self.broadcast(
+                    room_id,
+                    &serde_json::json!({
+                        "type": "vote result",
+                        "result": if winner.is_some() { "pass" } else { "failed" },
+                        "restaurant_name": winner,
+                        "scores": table,
+                    }),
+                );
+                self.finish_pool_vote(room_id, winner);
+            }
+            Some(VoteMode::Bracket) => {
+                let room_data = self.rooms.get_mut(room_id).unwrap();
+                let Some(bracket) = room_data.bracket.as_mut() else {
+                    return;
+                };
+                let Some((first, second)) = bracket.current_match() else {
+                    return;
+                };
+                let count = |name: &String| {
+                    room_data
+                        .ballots
+                        .values()
+                        .filter(|ballot| matches!(ballot, Ballot::Pick(pick) if pick == name))
+                        .count()
+                };
+                let (first_votes, second_votes) = (count(first), count(second));
+                bracket.record_match(first_votes, second_votes);
+                let champion = bracket.champion.clone();
+                room_data.ballots.clear();
+                if champion.is_none() {
+                    room_data.start_vote_timer();
+                }
+                self.send_bracket_state(room_id);
+                if let Some(champion) = champion {
+                    self.broadcast(
+                        room_id,
+                        &serde_json::json!({
+                            "type": "vote result",
+                            "result": "pass",
+                            "restaurant_name": champion,
+                        }),
+                    );
+                    let room_data = self.rooms.get_mut(room_id).unwrap();
+                    room_data.bracket = None;
+                    room_data.finish_round(champion);
                 }
             }
-        } else {
-            println!("Attempting to send current vote count but couldn't find room id.");
+            //時間到就跳過目前輪到的人
+            Some(VoteMode::Veto) => self.skip_veto_turn(room_id),
+            //轉盤直接抽，不會進到結算
+            Some(VoteMode::Roulette { .. }) => (),
+            None => println!("未設定投票模式"),
+        }
+        self.send_roster(room_id);
+    }
+    fn finish_single_vote(&mut self, room_id: &Uuid, passed: bool) {
+        let reject_list = self.rooms.get(room_id).unwrap().current_restaurant_vote.disagree_list.clone();
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        match room_data.current_restaurant.as_ref().map(|restaurant| restaurant.name.clone()) {
+            Some(restaurant_name) if passed => room_data.finish_round(restaurant_name),
+            _ => room_data.game_phase = GamePhase::Selection,
+        }
+        self.send_vote_result(room_id, if passed { "pass" } else { "failed" }, reject_list);
+    }
+    //沒有結果就回到收集階段，可以再加餐廳重新投票
+    fn finish_pool_vote(&mut self, room_id: &Uuid, winner: Option<String>) {
+        let room_data = self.rooms.get_mut(room_id).unwrap();
+        room_data.ballots.clear();
+        match winner {
+            Some(winner) => room_data.finish_round(winner),
+            None => room_data.game_phase = GamePhase::Collecting,
         }
     }
 }
 
 impl Actor for Lobby {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(VOTE_TICK_INTERVAL, |act, _| act.tick_vote_deadlines());
+        ctx.run_interval(ROOM_SWEEP_INTERVAL, |act, _| act.sweep_expired_rooms());
+    }
 }
 //<>裡面是被發送了甚麼消息要做出相應的handle()
 impl Handler<Disconnect> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        //已經被重新連線取代的舊連線，新的連線還在用這個id
+        if self.superseded.remove(&msg.id) {
+            return;
+        }
+        //房間可能已經過期被刪掉了
+        let Some(room_data) = self.rooms.get_mut(&msg.room_id) else {
+            self.sessions.remove(&msg.id);
+            self.closers.remove(&msg.id);
+            return;
+        };
+        room_data.last_activity = Instant::now();
+        if room_data.waitlist.contains(&msg.id) {
+            room_data.waitlist.retain(|id| *id != msg.id);
+            room_data.user_name_list.remove(&msg.id);
+            self.sessions.remove(&msg.id);
+            self.closers.remove(&msg.id);
+            self.send_waitlist_positions(&msg.room_id);
+            return;
+        }
+        if room_data.spectators.remove(&msg.id) {
+            room_data.user_name_list.remove(&msg.id);
+            self.sessions.remove(&msg.id);
+            self.closers.remove(&msg.id);
+            if room_data.is_abandoned() {
+                self.remove_room(&msg.room_id, "Room closed.");
+            } else {
+                self.send_roster(&msg.room_id);
+            }
+            return;
+        }
+        self.closers.remove(&msg.id);
         if self.sessions.remove(&msg.id).is_some() {
-            self.rooms
-                .get(&msg.room_id)
-                .unwrap()
-                .users
-                .iter()
-                .filter(|conn_id| *conn_id.to_owned() != msg.id)
-                .for_each(|user_id| {
-                    self.send_message(&format!("{} disconnected.", &msg.name), user_id)
-                });
-            self.rooms.get_mut(&msg.room_id).unwrap().users.remove(&msg.id);
-            self.rooms.get_mut(&msg.room_id).unwrap().user_name_list.remove(&msg.id);
-            if let Some(lobby) = self.rooms.get_mut(&msg.room_id) {
-                if lobby.users.len() > 1 {
-                    lobby.users.remove(&msg.id);
-                } else {
-                    //如果剩下一個人直接移除房間就好(能夠順便將他移除)
-                    self.remove_room_id(&msg.room_id);
-                    self.rooms.remove(&msg.room_id);
-                    println!("刪除房間，房間對應關係 {:?}", self.room_id_map);
+            //有reconnect token的人先保留位子跟投票，寬限時間內可以回來
+            let room_data = self.rooms.get(&msg.room_id).unwrap();
+            if room_data.reconnect_tokens.contains_key(&msg.id) && !room_data.disconnect_grace.is_zero() {
+                self.mark_away(&msg.room_id, &msg.id, ctx);
+            } else {
+                self.remove_member(&msg.room_id, &msg.id);
+            }
+        }
+    }
+}
+impl Handler<CreateRoom> for Lobby {
+    type Result = Result<CreatedRoom, CreateRoomError>;
+
+    fn handle(&mut self, msg: CreateRoom, _: &mut Context<Self>) -> Self::Result {
+        let mut room_id_map = self.room_id_map.lock().unwrap();
+        let code = match msg.code {
+            //還在使用中的房間號碼都在room_id_map裡，不會重複發出去
+            RoomCode::Generate(code_options) => code_options
+                .pick_free(|code| !room_id_map.contains_key(code))
+                .ok_or(CreateRoomError::CodesExhausted)?,
+            RoomCode::Named(name) => {
+                if room_id_map.contains_key(&name) {
+                    return Err(CreateRoomError::NameTaken(name));
                 }
+                name
             }
+        };
+        let room_id = Uuid::new_v5(&Uuid::NAMESPACE_DNS, code.as_bytes());
+        let mut room_data = RoomData::with_settings(msg.settings);
+        room_data.persistent = msg.persistent;
+        let invite_token = room_data
+            .is_protected()
+            .then(|| self.invites.sign(&room_data.room_salt, DEFAULT_INVITE_TTL));
+        let manage_key = msg.persistent.then(|| Uuid::new_v4().simple().to_string());
+        room_data.manage_key_hash = manage_key
+            .as_ref()
+            .map(|manage_key| hash_password(&room_data.room_salt, manage_key));
+        let host_key = Uuid::new_v4().simple().to_string();
+        room_data.host_key_hash = Some(hash_password(&room_data.room_salt, &host_key));
+        //先建好房間再公開號碼
+        self.rooms.insert(room_id, room_data);
+        room_id_map.insert(code.clone(), room_id);
+        println!("加入房間，房間對應關係 {:?}", room_id_map);
+        Ok(CreatedRoom {
+            code,
+            invite_token,
+            manage_key,
+            host_key,
+        })
+    }
+}
+
+impl Handler<ListRooms> for Lobby {
+    type Result = Vec<serde_json::Value>;
+
+    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let room_id_map = self.room_id_map.lock().unwrap();
+        let mut rooms: Vec<(&String, &RoomData)> = room_id_map
+            .iter()
+            .filter_map(|(code, room_id)| self.rooms.get(room_id).map(|room_data| (code, room_data)))
+            .filter(|(_, room_data)| room_data.persistent)
+            .collect();
+        rooms.sort_by_key(|(code, _)| *code);
+        rooms
+            .into_iter()
+            .map(|(code, room_data)| {
+                serde_json::json!({
+                    "room_id": code,
+                    "protected": room_data.is_protected(),
+                    "phase": room_data.game_phase.as_str(),
+                    "members": room_data.users.len(),
+                    "spectators": room_data.spectators.len(),
+                    "rounds": room_data.rounds.len(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Handler<DeleteRoom> for Lobby {
+    type Result = Admission;
+
+    fn handle(&mut self, msg: DeleteRoom, _: &mut Context<Self>) -> Self::Result {
+        //一般房間沒人就會自己消失，不能從這裡刪
+        let Some(room_data) = self.rooms.get(&msg.room_id).filter(|room_data| room_data.persistent) else {
+            return Admission::RoomNotFound;
+        };
+        let admission = match (&room_data.manage_key_hash, msg.manage_key) {
+            (_, None) => Admission::Unauthorized,
+            (Some(manage_key_hash), Some(manage_key))
+                if *manage_key_hash == hash_password(&room_data.room_salt, &manage_key) =>
+            {
+                Admission::Allowed
+            }
+            _ => Admission::Forbidden,
+        };
+        if matches!(admission, Admission::Allowed) {
+            self.remove_room(&msg.room_id, "Room deleted.");
+        }
+        admission
+    }
+}
+
+impl Handler<CheckAdmission> for Lobby {
+    type Result = Admission;
+
+    fn handle(&mut self, msg: CheckAdmission, _: &mut Context<Self>) -> Self::Result {
+        let Some(room_data) = self.rooms.get(&msg.room_id) else {
+            return Admission::RoomNotFound;
+        };
+        if msg.host_key.is_some_and(|host_key| room_data.is_host_key(&host_key))
+            || msg
+                .reconnect_token
+                .is_some_and(|token| room_data.find_reconnect(&token).is_some())
+        {
+            return Admission::Allowed;
+        }
+        //房間滿了也放行，Connect時會排進候補名單
+        room_data.check_credentials(&self.invites, msg.password.as_deref(), msg.token.as_deref())
+    }
+}
+
+impl Handler<GetRoomState> for Lobby {
+    type Result = Result<serde_json::Value, Admission>;
+
+    fn handle(&mut self, msg: GetRoomState, _: &mut Context<Self>) -> Self::Result {
+        let room_id = self.room_id_map.lock().unwrap().get(&msg.code).copied();
+        let Some(room_data) = room_id.and_then(|room_id| self.rooms.get(&room_id)) else {
+            return Err(Admission::RoomNotFound);
+        };
+        match room_data.check_credentials(&self.invites, msg.password.as_deref(), msg.token.as_deref()) {
+            Admission::Allowed => Ok(room_data.state(&msg.code)),
+            admission => Err(admission),
+        }
+    }
+}
+
+impl Handler<IssueInvite> for Lobby {
+    type Result = Result<(String, Duration), Admission>;
+
+    fn handle(&mut self, msg: IssueInvite, _: &mut Context<Self>) -> Self::Result {
+        let Some(room_data) = self.rooms.get(&msg.room_id) else {
+            return Err(Admission::RoomNotFound);
+        };
+        //只有邀請token的房間(沒有密碼)靠房主金鑰才能一直發新的token
+        let host_ok = msg.host_key.is_some_and(|host_key| room_data.is_host_key(&host_key));
+        let admission = if host_ok {
+            Admission::Allowed
+        } else {
+            room_data.check_credentials(&self.invites, msg.password.as_deref(), msg.token.as_deref())
+        };
+        match admission {
+            Admission::Allowed => {
+                //只靠token進來的不能發出比自己手上的token還晚到期的token
+                let mut ttl = msg.ttl;
+                if !host_ok && room_data.is_protected() && !room_data.password_matches(msg.password.as_deref()) {
+                    if let Some(remaining) = msg
+                        .token
+                        .as_deref()
+                        .and_then(|token| self.invites.remaining(&room_data.room_salt, token))
+                    {
+                        ttl = ttl.min(remaining);
+                    }
+                }
+                Ok((self.invites.sign(&room_data.room_salt, ttl), ttl))
+            }
+            admission => Err(admission),
         }
     }
 }
 //<>裡面是被發送了甚麼消息要做出相應的handle()
 impl Handler<Connect> for Lobby {
-    type Result = ();
+    type Result = Option<Resumed>;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        //entry是進入房間or_insert_with搭配entry如果進不去(沒有對應的key)，則創建新的房間並加入value
-        self.rooms
-            .entry(msg.lobby_id)
-            .or_insert_with(|| RoomData::new())
-            .users
-            .insert(msg.self_id);
+        //檢查完到連上之間房間可能已經被刪掉了
+        let Some(room_data) = self.rooms.get_mut(&msg.lobby_id) else {
+            msg.close_addr.do_send(CloseConnection {
+                reason: "Room not found.".to_string(),
+            });
+            return None;
+        };
+        room_data.last_activity = Instant::now();
+        let expired_token = match msg.reconnect_token.as_deref().filter(|_| !msg.spectator) {
+            Some(token) => {
+                let resumed = self.resume_session(&msg.lobby_id, token, msg.addr.clone(), msg.close_addr.clone());
+                if resumed.is_some() {
+                    return resumed;
+                }
+                true
+            }
+            None => false,
+        };
+        let room_data = self.rooms.get_mut(&msg.lobby_id).unwrap();
         //讓lobby知道使用者的id對應哪個ws地址，讓lobby廣播的時候可以知道要給誰
         self.sessions.insert(msg.self_id, msg.addr);
+        self.closers.insert(msg.self_id, msg.close_addr);
+        //旁觀者不佔名額
+        if msg.spectator {
+            room_data.spectators.insert(msg.self_id);
+            self.send_history(&msg.lobby_id, &msg.self_id);
+            return None;
+        }
+        if expired_token {
+            self.send_message("Your session has expired, please join again.", &msg.self_id);
+        }
+        let room_data = self.rooms.get_mut(&msg.lobby_id).unwrap();
+        if msg.host_key.is_some_and(|host_key| room_data.is_host_key(&host_key)) {
+            room_data.creator = Some(msg.self_id);
+        }
+        if room_data.is_full() {
+            room_data.waitlist.push_back(msg.self_id);
+            self.send_waitlist_positions(&msg.lobby_id);
+            return None;
+        }
+        self.admit_member(&msg.lobby_id, msg.self_id);
+        self.send_history(&msg.lobby_id, &msg.self_id);
+        None
+    }
+}
+impl Handler<JoinRoom> for Lobby {
+    type Result = Option<String>;
 
-        // self.send_message(&format!("your id is {}", msg.self_id), &msg.self_id);
-        //傳輸歷史紀錄給新進來的人知道
-        for (keys, value) in self.rooms.get(&msg.lobby_id).unwrap().data.iter() {
-            let restaurant_info: serde_json::Value =
-                serde_json::from_str(&value).expect("Failed to deserialize JSON");
-            let restaurant_name = restaurant_info["restaurant_name"]
-                .as_str()
-                .unwrap_or("Unknown");
-            let remark = restaurant_info["remark"].as_str().unwrap_or("");
-            self.send_message(&format!("{} suggest[restaurant: {}, remark: {}]", keys, restaurant_name, remark), &msg.self_id);
+    fn handle(&mut self, msg: JoinRoom, _: &mut Context<Self>) -> Self::Result {
+        let room_data = self.rooms.get_mut(&msg.room_id)?;
+        //不是這個房間的連線不處理
+        if !room_data.is_participant(&msg.id) {
+            return None;
         }
-        // self.send_message("--------------history~--------------", &msg.self_id);
+        room_data.last_activity = Instant::now();
+        let name = match nickname::normalize(&msg.name) {
+            Ok(name) => name,
+            Err(reason) => {
+                let payload = serde_json::json!({ "type": "error", "code": "invalid name", "message": reason });
+                self.send_payload(&payload, &msg.id);
+                return None;
+            }
+        };
+        //同一個房間不能有重複的名字(不分大小寫、全半形)，重複就自動加編號
+        let name = nickname::unique(&name, |key| {
+            room_data
+                .user_name_list
+                .iter()
+                .any(|(user_id, taken)| *user_id != msg.id && nickname::key(taken) == key)
+        });
+        room_data.user_name_list.insert(msg.id, name.clone());
+        self.send_payload(&serde_json::json!({ "type": "nickname", "name": name }), &msg.id);
+
+        let room_data = self.rooms.get(&msg.room_id).unwrap();
+        if room_data.waitlist.contains(&msg.id) {
+            return Some(name);
+        }
+        if room_data.spectators.contains(&msg.id) {
+            println!("{} is watching the room.", name);
+            self.send_join_message(&format!("{} is watching the room.", name), &msg.room_id);
+            self.send_roster(&msg.room_id);
+            return Some(name);
+        }
+        println!("{} join the room.", name);
+        self.send_join_message(&format!("{} join the room.", name), &msg.room_id);
+        if self.rooms.get(&msg.room_id).unwrap().host == Some(msg.id) {
+            self.send_host(&msg.room_id);
+        }
+        self.send_roster(&msg.room_id);
+        self.send_reconnect_token(&msg.room_id, &msg.id);
+        Some(name)
     }
 }
 //<>裡面是被發送了甚麼消息要做出相應的handle()
 impl Handler<ClientActorMessage> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientActorMessage, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: ClientActorMessage, ctx: &mut Context<Self>) -> Self::Result {
+        let Some(room_data) = self.rooms.get_mut(&msg.room_id) else {
+            return;
+        };
+        //不是這個房間的連線不處理
+        if !room_data.is_participant(&msg.id) {
+            return;
+        }
+        room_data.last_activity = Instant::now();
+        //候補中的人只能先報名字，其他操作等正式加入後才行
+        if self.rooms.get(&msg.room_id).unwrap().waitlist.contains(&msg.id) {
+            self.send_message("You are on the waiting list.", &msg.id);
+            return;
+        }
+        //旁觀者只能報名字跟聊天
+        if self.rooms.get(&msg.room_id).unwrap().spectators.contains(&msg.id)
+            && !matches!(msg.r#type, Type::Message)
+        {
+            self.send_message("Spectators can't vote or suggest restaurants.", &msg.id);
+            return;
+        }
         match msg.r#type {
-            Type::Join => {
-                println!("{} join the room.", msg.name);
-                self.rooms.get_mut(&msg.room_id).unwrap().user_name_list.insert(msg.id.clone(), msg.name.clone());
-                self.send_join_message(&format!("{} join the room.", msg.name), &msg.room_id);
-            }
             Type::Message => {
+                if self.rooms.get(&msg.room_id).unwrap().muted.contains(&msg.id) {
+                    self.send_message("You have been muted by the host.", &msg.id);
+                    return;
+                }
                 self.rooms
                     .get(&msg.room_id)
                     .unwrap()
-                    .users
-                    .iter()
+                    .audience()
                     .for_each(|client| {
                         self.send_message(&format!("{} say: {}", msg.name, msg.msg), client)
                     });
+                self.rooms
+                    .get(&msg.room_id)
+                    .unwrap()
+                    .record_missed_message(&format!("{} say: {}", msg.name, msg.msg));
                 self.rooms
                     .get_mut(&msg.room_id)
                     .unwrap()
                     .insert_data((msg.name, msg.msg))
             }
             Type::Vote => {
-                let room_data = self.rooms.get(&msg.room_id).unwrap();
-                match room_data.game_phase {
-                    GamePhase::Voting=>{
-                        self.rooms.get_mut(&msg.room_id).unwrap().current_restaurant_vote.vote(msg.id, &msg.msg);
-                        let room_data = self.rooms.get(&msg.room_id).unwrap();
-                        self.send_current_vote_count(&msg.room_id);
-                        //總投票數要等於人數才行，進行結果判斷
-                        if room_data.current_restaurant_vote.total_votes() == room_data.users.len() {
-                            if let Some(mode) = &room_data.vote_mode {
-                                match mode {
-                                    VoteMode::MajorityDecision=>{
-                                        if room_data.current_restaurant_vote.agree >= room_data.current_restaurant_vote.disagree{
-                                            self.send_vote_result(&msg.room_id, "pass", room_data.current_restaurant_vote.disagree_list.clone());
-                                            self.rooms.get_mut(&msg.room_id).unwrap().game_phase = GamePhase::Ending;
-                                        }
-                                        else{
-                                            self.send_vote_result(&msg.room_id, "failed", room_data.current_restaurant_vote.disagree_list.clone());
-                                            self.rooms.get_mut(&msg.room_id).unwrap().game_phase = GamePhase::Selection;
-                                        }
-                                    },
-                                    VoteMode::ConsensusDecision=>{
-                                        if room_data.current_restaurant_vote.disagree == 0{
-                                            self.send_vote_result(&msg.room_id, "pass", room_data.current_restaurant_vote.disagree_list.clone());
-                                            self.rooms.get_mut(&msg.room_id).unwrap().game_phase = GamePhase::Ending;
-                                        }
-                                        else{
-                                            self.send_vote_result(&msg.room_id, "failed", room_data.current_restaurant_vote.disagree_list.clone());
-                                            self.rooms.get_mut(&msg.room_id).unwrap().game_phase = GamePhase::Selection;
-                                        }
-                                    },
-                                }
-                            }else {
-                                println!("未設定投票模式")
-                            }
-                        }
-                    },
-                    _=>(),
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if matches!(room_data.game_phase, GamePhase::Voting) && !room_data.uses_candidate_pool() {
+                    if !room_data.current_restaurant_vote.vote(msg.id, &msg.msg) {
+                        return;
+                    }
+                    self.send_current_vote_count(&msg.room_id);
+                    self.send_roster(&msg.room_id);
+                    //總投票數要等於人數才行，進行結果判斷
+                    if self.rooms.get(&msg.room_id).unwrap().ready_to_close() {
+                        self.close_vote(&msg.room_id);
+                    }
+                }
+            },
+            Type::RetractVote => {
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if !room_data.is_voting() {
+                    return;
+                }
+                let retracted = if room_data.uses_candidate_pool() {
+                    room_data.ballots.remove(&msg.id).is_some()
+                } else {
+                    room_data.current_restaurant_vote.retract(msg.id)
+                };
+                if retracted {
+                    self.send_current_vote_count(&msg.room_id);
+                    self.send_roster(&msg.room_id);
+                }
+            },
+            Type::SetVoteLock(lock_votes) => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if room_data.is_voting() {
+                    self.send_message("The vote lock can't be changed during a vote.", &msg.id);
+                    return;
+                }
+                if !lock_votes && room_data.vote_timeout.is_none() {
+                    self.send_message("Votes can only be unlocked with a vote timeout.", &msg.id);
+                    return;
+                }
+                room_data.lock_votes = lock_votes;
+            },
+            Type::Star(restaurant_name) => {
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if !matches!(room_data.vote_mode, Some(VoteMode::Roulette { .. }))
+                    || !matches!(room_data.game_phase, GamePhase::Collecting)
+                {
+                    return;
+                }
+                if let Some(restaurant) = room_data.candidates.iter_mut().find(|c| c.name == restaurant_name) {
+                    if !restaurant.stars.remove(&msg.id) {
+                        restaurant.stars.insert(msg.id);
+                    }
+                    let stars = restaurant.stars.len();
+                    self.broadcast(
+                        &msg.room_id,
+                        &serde_json::json!({
+                            "type": "star count",
+                            "restaurant_name": restaurant_name,
+                            "stars": stars,
+                        }),
+                    );
                 }
             },
-            Type::SetVoteMode(vote_mode) => match self.rooms.get(&msg.room_id).unwrap().vote_mode {
-                None => {
-                    self.rooms.get_mut(&msg.room_id).unwrap().vote_mode = Some(vote_mode);
+            Type::SetSecretBallot(secret_ballot) => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if room_data.is_voting() {
+                    self.send_message("The secret ballot setting can't be changed during a vote.", &msg.id);
+                    return;
+                }
+                room_data.secret_ballot = secret_ballot;
+            },
+            Type::SetVoteMode(vote_mode) => {
+                if let Err(e) = vote_mode.validate() {
+                    self.send_message(&e, &msg.id);
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                //已經有人推薦餐廳的話，這一輪就照原本的方式投
+                if !matches!(room_data.game_phase, GamePhase::Waiting) {
+                    self.send_message("The vote mode can only be set before anyone suggests a restaurant.", &msg.id);
+                    return;
+                }
+                if room_data.vote_mode.is_none() {
+                    room_data.vote_mode = Some(vote_mode);
                 }
-                _ => (),
             },
             Type::AddRestaurant => {
                 let restaurant_info: serde_json::Value =
@@ -385,18 +1925,42 @@ impl Handler<ClientActorMessage> for Lobby {
                     .unwrap_or("Unknown");
                 let remark = restaurant_info["remark"].as_str().unwrap_or("");
                 println!("新的餐廳: {}, 備註: {}", restaurant_name, remark);
-                match room_data.game_phase {
-                    GamePhase::Waiting => {
-                        self.rooms.get_mut(&msg.room_id).unwrap().game_phase = GamePhase::Voting;
-                        self.send_selection_restaurant(&msg.room_id, restaurant_name, remark);
-                        self.rooms
-                            .get_mut(&msg.room_id)
-                            .unwrap()
-                            .insert_data((msg.name, msg.msg))
+                if room_data.uses_candidate_pool() {
+                    if !matches!(room_data.game_phase, GamePhase::Waiting | GamePhase::Collecting) {
+                        self.send_message("Suggestions are closed while the room is voting.", &msg.id);
+                        return;
+                    }
+                    if room_data.candidates.iter().any(|c| c.name == restaurant_name) {
+                        self.send_message(&format!("{} is already a candidate.", restaurant_name), &msg.id);
+                        return;
                     }
-                    GamePhase::Selection => {
-                        self.rooms.get_mut(&msg.room_id).unwrap().game_phase = GamePhase::Voting;
+                    let restaurant = Restaurant {
+                        name: restaurant_name.to_string(),
+                        remark: remark.to_string(),
+                        stars: HashSet::new(),
+                    };
+                    let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                    room_data.game_phase = GamePhase::Collecting;
+                    room_data.candidates.push(restaurant);
+                    self.commit_roulette_seed(&msg.room_id);
+                    self.send_selection_restaurant(&msg.room_id, restaurant_name, remark);
+                    self.rooms
+                        .get_mut(&msg.room_id)
+                        .unwrap()
+                        .insert_data((msg.name, msg.msg));
+                    return;
+                }
+                match room_data.game_phase {
+                    GamePhase::Waiting | GamePhase::Selection => {
+                        self.rooms.get_mut(&msg.room_id).unwrap().current_restaurant = Some(Restaurant {
+                            name: restaurant_name.to_string(),
+                            remark: remark.to_string(),
+                            stars: HashSet::new(),
+                        });
+                        self.rooms.get_mut(&msg.room_id).unwrap().current_restaurant_vote = VoteData::new();
+                        self.rooms.get_mut(&msg.room_id).unwrap().begin_voting();
                         self.send_selection_restaurant(&msg.room_id, restaurant_name, remark);
+                        self.send_roster(&msg.room_id);
                         self.rooms
                             .get_mut(&msg.room_id)
                             .unwrap()
@@ -405,6 +1969,176 @@ impl Handler<ClientActorMessage> for Lobby {
                     _ => (),
                 }
             }
+            Type::StartVote => self.start_pool_vote(&msg.room_id, &msg.id, ctx),
+            Type::Rank(ranking) => {
+                let room_data = self.rooms.get(&msg.room_id).unwrap();
+                if !matches!(room_data.vote_mode, Some(VoteMode::RankedChoice)) {
+                    return;
+                }
+                if !validate_ranking(&room_data.candidates, &ranking) {
+                    self.send_message("Rank every restaurant exactly once.", &msg.id);
+                    return;
+                }
+                self.cast_ballot(&msg.room_id, msg.id, Ballot::Ranking(ranking));
+            }
+            Type::Approve(restaurant_names) => {
+                let room_data = self.rooms.get(&msg.room_id).unwrap();
+                if !matches!(room_data.vote_mode, Some(VoteMode::Approval)) {
+                    return;
+                }
+                if !validate_approval(&room_data.candidates, &restaurant_names) {
+                    self.send_message("Invalid approval ballot.", &msg.id);
+                    return;
+                }
+                self.cast_ballot(&msg.room_id, msg.id, Ballot::Approval(restaurant_names));
+            }
+            Type::Score(scores) => {
+                let room_data = self.rooms.get(&msg.room_id).unwrap();
+                if !matches!(room_data.vote_mode, Some(VoteMode::BordaCount)) {
+                    return;
+                }
+                if !validate_scores(&room_data.candidates, &scores) {
+                    self.send_message(
+                        &format!(
+                            "Score every restaurant from 0 to {}, using each score once.",
+                            room_data.candidates.len().saturating_sub(1)
+                        ),
+                        &msg.id,
+                    );
+                    return;
+                }
+                self.cast_ballot(&msg.room_id, msg.id, Ballot::Scores(scores));
+            }
+            Type::Pick(restaurant_name) => {
+                let room_data = self.rooms.get(&msg.room_id).unwrap();
+                let in_match = room_data
+                    .bracket
+                    .as_ref()
+                    .and_then(|bracket| bracket.current_match())
+                    .is_some_and(|(first, second)| *first == restaurant_name || *second == restaurant_name);
+                if !in_match {
+                    self.send_message("That restaurant isn't in the current match.", &msg.id);
+                    return;
+                }
+                self.cast_ballot(&msg.room_id, msg.id, Ballot::Pick(restaurant_name));
+            }
+            Type::Strike(restaurant_name) => {
+                let user_name = self
+                    .rooms
+                    .get(&msg.room_id)
+                    .unwrap()
+                    .user_name_list
+                    .get(&msg.id)
+                    .cloned()
+                    .unwrap_or(msg.name);
+                self.strike_restaurant(&msg.room_id, &msg.id, user_name, restaurant_name);
+            }
+            Type::Kick(name) => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let Some(user_id) = self.rooms.get(&msg.room_id).unwrap().find_member(&name) else {
+                    self.send_message(&format!("{} isn't in the room.", name), &msg.id);
+                    return;
+                };
+                if user_id == msg.id {
+                    return;
+                }
+                //被踢的人不保留位子，token作廢後關閉連線會走Disconnect直接移出房間
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                room_data.reconnect_tokens.remove(&user_id);
+                if room_data.away.contains_key(&user_id) {
+                    self.remove_member(&msg.room_id, &user_id);
+                } else if let Some(closer) = self.closers.get(&user_id) {
+                    closer.do_send(CloseConnection {
+                        reason: "You were removed by the host.".to_string(),
+                    });
+                }
+            }
+            Type::Mute(name, muted) => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                let Some(user_id) = room_data.find_member(&name) else {
+                    self.send_message(&format!("{} isn't in the room.", name), &msg.id);
+                    return;
+                };
+                if muted {
+                    room_data.muted.insert(user_id);
+                } else {
+                    room_data.muted.remove(&user_id);
+                }
+                self.broadcast(
+                    &msg.room_id,
+                    &serde_json::json!({ "type": "mute", "name": name, "muted": muted }),
+                );
+            }
+            Type::ForceCloseVote => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if room_data.is_voting() {
+                    room_data.apply_missing_votes();
+                    self.close_vote(&msg.room_id);
+                }
+            }
+            Type::TransferHost(name) => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                let Some(user_id) = room_data.find_member(&name) else {
+                    self.send_message(&format!("{} isn't in the room.", name), &msg.id);
+                    return;
+                };
+                room_data.host = Some(user_id);
+                self.send_host(&msg.room_id);
+                self.send_roster(&msg.room_id);
+            }
+            Type::NewRound => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if room_data.is_voting() {
+                    self.send_message("A new round can't start during a vote.", &msg.id);
+                    return;
+                }
+                room_data.reset_round();
+                println!("開始新的一輪: {}", room_data.rounds.len() + 1);
+                let payload = serde_json::json!({
+                    "type": "new round",
+                    "round": room_data.rounds.len() + 1,
+                    "rounds": room_data.rounds,
+                });
+                self.broadcast(&msg.room_id, &payload);
+                self.send_roster(&msg.room_id);
+            }
+            Type::SetVoteTimeout(timeout, missing_vote) => {
+                if !self.require_host(&msg.room_id, &msg.id) {
+                    return;
+                }
+                let room_data = self.rooms.get_mut(&msg.room_id).unwrap();
+                if room_data.is_voting() {
+                    self.send_message("The vote timeout can't be changed during a vote.", &msg.id);
+                    return;
+                }
+                if timeout.is_some_and(|timeout| timeout > MAX_VOTE_TIMEOUT) {
+                    self.send_message(
+                        &format!("The vote timeout can't be more than {} seconds.", MAX_VOTE_TIMEOUT.as_secs()),
+                        &msg.id,
+                    );
+                    return;
+                }
+                if timeout.is_none() && !room_data.lock_votes {
+                    self.send_message("Lock votes before removing the vote timeout.", &msg.id);
+                    return;
+                }
+                room_data.vote_timeout = timeout;
+                room_data.missing_vote = missing_vote;
+            }
         }
     }
 }
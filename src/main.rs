@@ -3,10 +3,18 @@ mod ws;
 use lobby::Lobby;
 mod messages;
 mod api;
+mod vote;
+mod invite;
+mod room_code;
+mod words;
+mod nickname;
 use actix::Actor;
 use actix_cors::Cors;
 use actix_web::{App, HttpServer,web::Data};
 use api::get_room_id;
+use api::create_invite;
+use api::get_room_state;
+use api::{create_persistent_room, delete_persistent_room, list_persistent_rooms};
 use api::start_connection as start_connection_route;
 use std::collections::HashMap;
 use std::sync::Mutex;
@@ -46,9 +54,15 @@ async fn main() -> std::io::Result<()> {
                     .allow_any_method()
                     .allow_any_header(),
             )
+            //GET /rooms要比/{group_id}先註冊，不然會被當成房間號碼
+            .service(list_persistent_rooms)
             .service(start_connection_route) //register our route. rename with "as" import or naming conflict
             .app_data(Data::new(chat_server.clone())) //register the lobby
             .service(get_room_id)
+            .service(create_invite)
+            .service(get_room_state)
+            .service(create_persistent_room)
+            .service(delete_persistent_room)
             .app_data(room_id_map.clone())
     })
     .bind("127.0.0.1:8080")?
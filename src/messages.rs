@@ -1,16 +1,39 @@
-use actix::prelude::{Message, Recipient};
+use crate::room_code::CodeOptions;
+use actix::prelude::{Message, MessageResponse, Recipient};
 use uuid::Uuid;
 use serde::{Deserialize,Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct WsMessage(pub String);
 
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Option<Resumed>")]
 pub struct Connect {
     pub addr: Recipient<WsMessage>,
+    pub close_addr: Recipient<CloseConnection>,
     pub lobby_id: Uuid,
     pub self_id: Uuid,
+    //旁觀者只看不投票
+    pub spectator: bool,
+    //斷線前拿到的reconnect token
+    pub reconnect_token: Option<String>,
+    //建立房間時拿到的房主金鑰
+    pub host_key: Option<String>,
+}
+
+//用reconnect token接回原本的身分
+pub struct Resumed {
+    pub id: Uuid,
+    pub name: String,
+}
+
+//Lobby要求關閉連線，例如被房主踢出
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseConnection {
+    pub reason: String,
 }
 
 #[derive(Message)]
@@ -18,14 +41,36 @@ pub struct Connect {
 pub struct Disconnect {
     pub id: Uuid,
     pub room_id: Uuid,
-    pub name: String,
 }
 pub enum Type {
-    Join,
     Message,
     Vote,
     SetVoteMode(VoteMode),
-    AddRestaurant
+    AddRestaurant,
+    StartVote,
+    Rank(Vec<String>),
+    Approve(HashSet<String>),
+    SetVoteTimeout(Option<Duration>, MissingVotePolicy),
+    RetractVote,
+    SetVoteLock(bool),
+    SetSecretBallot(bool),
+    Star(String),
+    Score(HashMap<String, usize>),
+    Pick(String),
+    Strike(String),
+    Kick(String),
+    Mute(String, bool),
+    ForceCloseVote,
+    TransferHost(String),
+    NewRound,
+}
+//報名字加入，名字通過檢查的話回傳最後使用的名字(重複時會自動加編號)
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+pub struct JoinRoom {
+    pub id: Uuid,
+    pub room_id: Uuid,
+    pub name: String,
 }
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -36,7 +81,7 @@ pub struct ClientActorMessage {
     pub room_id: Uuid,
     pub name: String,
 }
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub enum VoteMode {
     //多數決
     #[serde(rename = "majority decision")]
@@ -44,6 +89,234 @@ pub enum VoteMode {
     //合意投票
     #[serde(rename = "consensus decision")]
     ConsensusDecision,
+    //排序複選(即時決選)
+    #[serde(rename = "ranked choice")]
+    RankedChoice,
+    //認可投票
+    #[serde(rename = "approval")]
+    Approval,
+    //門檻投票：贊成比例要達到percent(%)，且投票數至少要quorum票
+    #[serde(rename = "threshold")]
+    Threshold {
+        percent: u8,
+        #[serde(default)]
+        quorum: Option<usize>,
+    },
+    //午餐轉盤：不投票直接抽，weighted時每顆星號多一份機率
+    #[serde(rename = "lunch roulette")]
+    Roulette {
+        #[serde(default)]
+        weighted: bool,
+    },
+    //波達計分
+    #[serde(rename = "borda count")]
+    BordaCount,
+    //淘汰賽，兩兩對決
+    #[serde(rename = "bracket")]
+    Bracket,
+    //輪流否決，每人劃掉一間直到剩一間
+    #[serde(rename = "veto")]
+    Veto,
+}
+
+impl VoteMode {
+    pub fn validate(&self) -> Result<(), String> {
+        if let VoteMode::Threshold { percent, .. } = self {
+            if *percent == 0 || *percent > 100 {
+                return Err("The threshold must be between 1 and 100 percent.".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    //先收集多間候選餐廳再一起投票的模式
+    pub fn uses_candidate_pool(&self) -> bool {
+        matches!(
+            self,
+            VoteMode::RankedChoice
+                | VoteMode::Approval
+                | VoteMode::Roulette { .. }
+                | VoteMode::BordaCount
+                | VoteMode::Bracket
+                | VoteMode::Veto
+        )
+    }
+}
+
+//投票時間到時，還沒投票的人要怎麼算
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub enum MissingVotePolicy {
+    #[default]
+    #[serde(rename = "abstain")]
+    Abstain,
+    #[serde(rename = "agree")]
+    Agree,
+    #[serde(rename = "disagree")]
+    Disagree,
+}
+
+pub const SAVE_MESSAGE_MAX_LEN: usize = 50;
+const MAX_HISTORY_LEN: usize = 500;
+//投票限時最多一天
+pub const MAX_VOTE_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+//斷線後保留位子的秒數
+pub const DEFAULT_DISCONNECT_GRACE: u64 = 2 * 60;
+const MAX_DISCONNECT_GRACE: u64 = 30 * 60;
+
+//建立房間時可以帶的設定，沒給的欄位用預設值
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomSettings {
+    pub vote_mode: Option<VoteMode>,
+    pub max_members: Option<usize>,
+    //投票限時(秒)，沒給就不限時
+    pub vote_timeout: Option<u64>,
+    pub missing_vote: MissingVotePolicy,
+    pub lock_votes: bool,
+    //保留幾筆聊天跟推薦紀錄給後來加入的人
+    pub history_len: usize,
+    pub secret_ballot: bool,
+    //有密碼的話，連線時要帶password或邀請token
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    //只能用邀請token加入
+    pub invite_only: bool,
+    //斷線後保留位子幾秒，期間顯示為離開中，0代表馬上移除
+    pub disconnect_grace: u64,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            vote_mode: None,
+            max_members: None,
+            vote_timeout: None,
+            missing_vote: MissingVotePolicy::Abstain,
+            lock_votes: true,
+            history_len: SAVE_MESSAGE_MAX_LEN,
+            secret_ballot: false,
+            password: None,
+            invite_only: false,
+            disconnect_grace: DEFAULT_DISCONNECT_GRACE,
+        }
+    }
+}
+
+impl RoomSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(vote_mode) = &self.vote_mode {
+            vote_mode.validate()?;
+        }
+        if self.max_members == Some(0) {
+            return Err("max_members must be at least 1.".to_string());
+        }
+        if self.vote_timeout == Some(0) {
+            return Err("vote_timeout must be at least 1 second.".to_string());
+        }
+        if self.vote_timeout.is_some_and(|timeout| timeout > MAX_VOTE_TIMEOUT.as_secs()) {
+            return Err(format!("vote_timeout can't be more than {} seconds.", MAX_VOTE_TIMEOUT.as_secs()));
+        }
+        //不鎖票要等到期限才結算，沒有期限的話投票永遠不會結束
+        if !self.lock_votes && self.vote_timeout.is_none() {
+            return Err("lock_votes can only be false with a vote_timeout.".to_string());
+        }
+        if self.history_len > MAX_HISTORY_LEN {
+            return Err(format!("history_len can't be more than {}.", MAX_HISTORY_LEN));
+        }
+        if self.password.as_ref().is_some_and(|password| password.is_empty()) {
+            return Err("password can't be empty.".to_string());
+        }
+        if self.disconnect_grace > MAX_DISCONNECT_GRACE {
+            return Err(format!("disconnect_grace can't be more than {} seconds.", MAX_DISCONNECT_GRACE));
+        }
+        Ok(())
+    }
+}
+
+//房間號碼由Lobby挑，房間建好之後才登記到room_id_map，連線不會找到還沒建好的房間
+pub enum RoomCode {
+    Generate(CodeOptions),
+    //固定房間直接用名稱當號碼
+    Named(String),
+}
+
+pub enum CreateRoomError {
+    CodesExhausted,
+    NameTaken(String),
+}
+
+//有保護的房間會附一組邀請token，固定房間會附一組刪除用的管理金鑰，另外每個房間都有房主金鑰，都只給建立的人
+pub struct CreatedRoom {
+    pub code: String,
+    pub invite_token: Option<String>,
+    pub manage_key: Option<String>,
+    //帶著這個金鑰連線的人就是房主
+    pub host_key: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<CreatedRoom, CreateRoomError>")]
+pub struct CreateRoom {
+    pub code: RoomCode,
+    pub settings: RoomSettings,
+    //固定房間最後一個人離開也不會刪除
+    pub persistent: bool,
+}
+
+//列出所有固定房間
+#[derive(Message)]
+#[rtype(result = "Vec<serde_json::Value>")]
+pub struct ListRooms;
+
+//刪除固定房間，要帶建立時拿到的管理金鑰
+#[derive(Message)]
+#[rtype(result = "Admission")]
+pub struct DeleteRoom {
+    pub room_id: Uuid,
+    pub manage_key: Option<String>,
+}
+
+#[derive(MessageResponse)]
+pub enum Admission {
+    Allowed,
+    RoomNotFound,
+    //有保護但沒帶密碼或token
+    Unauthorized,
+    //密碼或token錯誤
+    Forbidden,
+}
+
+//握手前先問Lobby能不能進房間
+#[derive(Message)]
+#[rtype(result = "Admission")]
+pub struct CheckAdmission {
+    pub room_id: Uuid,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    //房主金鑰不會過期，邀請token都過期了建立的人還是進得去
+    pub host_key: Option<String>,
+    //位子還保留著的人帶reconnect token就能回來，不用再驗證一次
+    pub reconnect_token: Option<String>,
+}
+
+//查詢房間目前的狀態，有保護的房間一樣要帶密碼或token
+#[derive(Message)]
+#[rtype(result = "Result<serde_json::Value, Admission>")]
+pub struct GetRoomState {
+    pub code: String,
+    pub password: Option<String>,
+    pub token: Option<String>,
+}
+
+//用密碼、房主金鑰或手上的token換一組新的邀請token
+#[derive(Message)]
+#[rtype(result = "Result<(String, Duration), Admission>")]
+pub struct IssueInvite {
+    pub room_id: Uuid,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub host_key: Option<String>,
+    pub ttl: Duration,
 }
 
 #[derive(Deserialize)]
@@ -60,8 +333,48 @@ pub enum IncomingMessage {
         restaurant_name:String,
         remark:String,
     },
+    //已經投過的話會改票
     #[serde(rename = "vote")]
     Vote{is_agree:bool},
+    #[serde(rename = "retract vote")]
+    RetractVote,
+    #[serde(rename = "start vote")]
+    StartVote,
+    #[serde(rename = "rank")]
+    Rank{ranking:Vec<String>},
+    #[serde(rename = "approve")]
+    Approve{restaurant_names:HashSet<String>},
+    //再按一次取消
+    #[serde(rename = "star")]
+    Star{restaurant_name:String},
+    #[serde(rename = "score")]
+    Score{scores:HashMap<String,usize>},
+    #[serde(rename = "pick")]
+    Pick{restaurant_name:String},
+    #[serde(rename = "strike")]
+    Strike{restaurant_name:String},
+    //以下只有房主可以用
+    //seconds為0代表不限時
+    #[serde(rename = "set vote timeout")]
+    SetVoteTimeout{
+        seconds:u64,
+        #[serde(default)]
+        missing_vote:MissingVotePolicy,
+    },
+    #[serde(rename = "set vote lock")]
+    SetVoteLock{lock:bool},
+    #[serde(rename = "set secret ballot")]
+    SetSecretBallot{secret_ballot:bool},
+    #[serde(rename = "kick")]
+    Kick{name:String},
+    #[serde(rename = "mute")]
+    Mute{name:String, muted:bool},
+    #[serde(rename = "force close vote")]
+    ForceCloseVote,
+    #[serde(rename = "transfer host")]
+    TransferHost{name:String},
+    #[serde(rename = "new round")]
+    NewRound,
 }
 
 #[derive(Serialize)]
@@ -0,0 +1,117 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+//暱稱最多幾個字(NFKC正規化之後)
+pub const MAX_NAME_LEN: usize = 20;
+
+//看不見的字元(Unicode的Default_Ignorable_Code_Point)，像零寬空格U+200B，加在名字裡看起來一樣但比對不出來
+const INVISIBLE_RANGES: [(char, char); 17] = [
+    ('\u{00AD}', '\u{00AD}'),
+    ('\u{034F}', '\u{034F}'),
+    ('\u{061C}', '\u{061C}'),
+    ('\u{115F}', '\u{1160}'),
+    ('\u{17B4}', '\u{17B5}'),
+    ('\u{180B}', '\u{180F}'),
+    ('\u{200B}', '\u{200F}'),
+    ('\u{202A}', '\u{202E}'),
+    ('\u{2060}', '\u{206F}'),
+    ('\u{3164}', '\u{3164}'),
+    ('\u{FE00}', '\u{FE0F}'),
+    ('\u{FEFF}', '\u{FEFF}'),
+    ('\u{FFA0}', '\u{FFA0}'),
+    ('\u{FFF0}', '\u{FFF8}'),
+    ('\u{1BCA0}', '\u{1BCA3}'),
+    ('\u{1D173}', '\u{1D17A}'),
+    ('\u{E0000}', '\u{E0FFF}'),
+];
+
+fn is_invisible(c: char) -> bool {
+    INVISIBLE_RANGES.iter().any(|(start, end)| (*start..=*end).contains(&c))
+}
+
+//去掉前後空白、做NFKC正規化，全形跟半形之類長得一樣的字會變成同一個
+pub fn normalize(name: &str) -> Result<String, String> {
+    let name: String = name.trim().nfkc().collect();
+    if name.is_empty() {
+        return Err("Name can't be empty.".to_string());
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        return Err(format!("Name can't be longer than {} characters.", MAX_NAME_LEN));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("Name can't contain control characters.".to_string());
+    }
+    if name.chars().any(is_invisible) {
+        return Err("Name can't contain invisible characters.".to_string());
+    }
+    Ok(name)
+}
+
+//比對是否重複用的key，不分大小寫，長得像的字(例如西里爾字母А跟拉丁字母A)也算同一個
+pub fn key(name: &str) -> String {
+    let lowercase: String = name.nfkc().flat_map(char::to_lowercase).collect();
+    skeleton(&lowercase).collect()
+}
+
+//名字被用走的話自動在後面加上編號，例如 Alex 2
+pub fn unique(name: &str, is_taken: impl Fn(&str) -> bool) -> String {
+    if !is_taken(&key(name)) {
+        return name.to_string();
+    }
+    (2..)
+        .map(|n| {
+            let suffix = format!(" {}", n);
+            //加上編號後一樣不能超過長度限制
+            let base: String = name
+                .chars()
+                .take(MAX_NAME_LEN - suffix.chars().count())
+                .collect();
+            format!("{}{}", base.trim_end(), suffix)
+        })
+        .find(|candidate| !is_taken(&key(candidate)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_truncates_the_base_to_fit_the_suffix() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let taken = [key(&name)];
+        let renamed = unique(&name, |key| taken.iter().any(|taken| taken == key));
+        assert_eq!(renamed, format!("{} 2", "a".repeat(MAX_NAME_LEN - 2)));
+        assert_eq!(renamed.chars().count(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn unique_skips_numbers_already_in_use() {
+        let taken = [key("Alex"), key("alex 2")];
+        assert_eq!(unique("ALEX", |key| taken.iter().any(|taken| taken == key)), "ALEX 3");
+        assert_eq!(unique("Sam", |key| taken.iter().any(|taken| taken == key)), "Sam");
+    }
+
+    #[test]
+    fn unique_does_not_leave_a_double_space_after_truncation() {
+        //截斷後結尾剛好是空白的話先去掉
+        let name = format!("{} b", "a".repeat(MAX_NAME_LEN - 3));
+        let taken = [key(&name)];
+        let renamed = unique(&name, |key| taken.iter().any(|taken| taken == key));
+        assert_eq!(renamed, format!("{} 2", "a".repeat(MAX_NAME_LEN - 3)));
+    }
+
+    #[test]
+    fn normalize_rejects_invisible_characters() {
+        assert!(normalize("bo\u{200B}b").is_err());
+        assert!(normalize("\u{3164}").is_err());
+        assert_eq!(normalize("  Ｂｏｂ  ").as_deref(), Ok("Bob"));
+    }
+
+    #[test]
+    fn key_matches_confusable_letters() {
+        //西里爾字母А跟拉丁字母A
+        assert_eq!(key("\u{0410}my"), key("amy"));
+        assert_ne!(key("amy"), key("ann"));
+    }
+}
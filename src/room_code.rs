@@ -0,0 +1,179 @@
+use crate::words::{ADJECTIVES, NOUNS};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Deserialize;
+
+//數字號碼預設5位數(10000..99999)
+const DEFAULT_DIGITS: usize = 5;
+const MIN_DIGITS: usize = 4;
+const MAX_DIGITS: usize = 9;
+//文字號碼預設兩個字，例如 spicy-noodle-42
+const DEFAULT_WORDS: usize = 2;
+const MAX_WORDS: usize = 4;
+//文字號碼最後面接的數字範圍
+const WORD_SUFFIX_MIN: u32 = 10;
+const WORD_SUFFIX_MAX: u32 = 99;
+//隨機挑幾次都撞到的話，改成依序找空的號碼
+const RANDOM_CODE_ATTEMPTS: usize = 100;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub enum CodeFormat {
+    #[default]
+    #[serde(rename = "numeric")]
+    Numeric,
+    #[serde(rename = "words")]
+    Words,
+}
+
+//建立房間時帶 ?format=words&length=3 之類的參數，都不帶就是原本的5位數字
+#[derive(Deserialize)]
+pub struct CodeOptions {
+    #[serde(default)]
+    pub format: CodeFormat,
+    //數字是幾位數，文字是幾個單字
+    pub length: Option<usize>,
+}
+
+impl CodeOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.format {
+            CodeFormat::Numeric => {
+                let digits = self.length.unwrap_or(DEFAULT_DIGITS);
+                if !(MIN_DIGITS..=MAX_DIGITS).contains(&digits) {
+                    return Err(format!(
+                        "Numeric room codes must be {} to {} digits long.",
+                        MIN_DIGITS, MAX_DIGITS
+                    ));
+                }
+            }
+            CodeFormat::Words => {
+                let words = self.length.unwrap_or(DEFAULT_WORDS);
+                if !(1..=MAX_WORDS).contains(&words) {
+                    return Err(format!("Word room codes must be 1 to {} words long.", MAX_WORDS));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    //挑一個is_free回傳true的號碼，全部都被用掉就回傳None
+    pub fn pick_free(&self, is_free: impl Fn(&str) -> bool) -> Option<String> {
+        let mut rng = rand::thread_rng();
+        let code = (0..RANDOM_CODE_ATTEMPTS)
+            .map(|_| self.generate(&mut rng))
+            .find(|code| is_free(code));
+        match self.format {
+            CodeFormat::Numeric => code.or_else(|| {
+                let (min, max) = self.numeric_range();
+                (min..=max).map(|code| code.to_string()).find(|code| is_free(code))
+            }),
+            CodeFormat::Words => code.or_else(|| {
+                (0..self.word_space())
+                    .map(|index| self.word_code(index))
+                    .find(|code| is_free(code))
+            }),
+        }
+    }
+
+    fn generate(&self, rng: &mut impl Rng) -> String {
+        match self.format {
+            CodeFormat::Numeric => {
+                let (min, max) = self.numeric_range();
+                rng.gen_range(min..=max).to_string()
+            }
+            CodeFormat::Words => {
+                let words = self.length.unwrap_or(DEFAULT_WORDS);
+                //前面都是形容詞，最後一個是名詞，再接一個兩位數
+                let mut parts: Vec<String> = ADJECTIVES
+                    .choose_multiple(rng, words - 1)
+                    .map(|word| word.to_string())
+                    .collect();
+                parts.push(NOUNS.choose(rng).unwrap().to_string());
+                parts.push(rng.gen_range(WORD_SUFFIX_MIN..=WORD_SUFFIX_MAX).to_string());
+                parts.join("-")
+            }
+        }
+    }
+
+    //文字號碼總共有幾種組合：不重複的形容詞排列 x 名詞 x 數字
+    fn word_space(&self) -> u64 {
+        let words = self.length.unwrap_or(DEFAULT_WORDS);
+        let adjectives: u64 = (0..words - 1)
+            .map(|i| (ADJECTIVES.len() - i) as u64)
+            .product();
+        adjectives * NOUNS.len() as u64 * u64::from(WORD_SUFFIX_MAX - WORD_SUFFIX_MIN + 1)
+    }
+
+    //把0..word_space()的編號換成對應的文字號碼，每個編號剛好對到一個號碼
+    fn word_code(&self, mut index: u64) -> String {
+        let words = self.length.unwrap_or(DEFAULT_WORDS);
+        let suffix_count = u64::from(WORD_SUFFIX_MAX - WORD_SUFFIX_MIN + 1);
+        let suffix = WORD_SUFFIX_MIN + (index % suffix_count) as u32;
+        index /= suffix_count;
+        let noun = NOUNS[(index % NOUNS.len() as u64) as usize];
+        index /= NOUNS.len() as u64;
+        let mut adjectives: Vec<&str> = ADJECTIVES.to_vec();
+        let mut parts: Vec<String> = Vec::with_capacity(words + 1);
+        for _ in 0..words - 1 {
+            let pick = (index % adjectives.len() as u64) as usize;
+            index /= adjectives.len() as u64;
+            parts.push(adjectives.remove(pick).to_string());
+        }
+        parts.push(noun.to_string());
+        parts.push(suffix.to_string());
+        parts.join("-")
+    }
+
+    fn numeric_range(&self) -> (u64, u64) {
+        let digits = self.length.unwrap_or(DEFAULT_DIGITS) as u32;
+        (10u64.pow(digits - 1), 10u64.pow(digits) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn words(length: usize) -> CodeOptions {
+        CodeOptions {
+            format: CodeFormat::Words,
+            length: Some(length),
+        }
+    }
+
+    //形容詞不重複、最後是名詞跟兩位數
+    fn is_word_code(code: &str, length: usize) -> bool {
+        let parts: Vec<&str> = code.split('-').collect();
+        let Some((suffix, rest)) = parts.split_last() else {
+            return false;
+        };
+        let Some((noun, adjectives)) = rest.split_last() else {
+            return false;
+        };
+        let distinct: HashSet<&&str> = adjectives.iter().collect();
+        adjectives.len() == length - 1
+            && distinct.len() == adjectives.len()
+            && adjectives.iter().all(|word| ADJECTIVES.contains(word))
+            && NOUNS.contains(noun)
+            && suffix
+                .parse::<u32>()
+                .is_ok_and(|suffix| (WORD_SUFFIX_MIN..=WORD_SUFFIX_MAX).contains(&suffix))
+    }
+
+    #[test]
+    fn word_codes_enumerate_every_combination_once() {
+        for length in [1, 2] {
+            let options = words(length);
+            let codes: HashSet<String> = (0..options.word_space())
+                .map(|index| options.word_code(index))
+                .collect();
+            assert_eq!(codes.len() as u64, options.word_space());
+            assert!(codes.iter().all(|code| is_word_code(code, length)));
+            let mut rng = rand::thread_rng();
+            let generated = options.generate(&mut rng);
+            assert!(is_word_code(&generated, length));
+            assert!(codes.contains(&generated));
+        }
+    }
+}
@@ -0,0 +1,532 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+//轉盤動畫至少轉幾圈
+const ROULETTE_LAPS: usize = 3;
+
+//候選餐廳
+pub struct Restaurant {
+    pub name: String,
+    pub remark: String,
+    //幫這間加星號的人，轉盤加權用
+    pub stars: HashSet<Uuid>,
+}
+
+//每個人投出的選票內容，依投票模式不同
+pub enum Ballot {
+    //排序複選，越前面越想去
+    Ranking(Vec<String>),
+    //認可投票，可以同時贊成任意幾間
+    Approval(HashSet<String>),
+    //波達計分，每間餐廳給0到N-1分
+    Scores(HashMap<String, usize>),
+    //淘汰賽中二選一
+    Pick(String),
+}
+
+#[derive(Serialize)]
+pub struct CandidateCount {
+    pub restaurant_name: String,
+    pub votes: usize,
+}
+
+#[derive(Serialize)]
+pub struct RunoffRound {
+    pub round: usize,
+    pub tally: Vec<CandidateCount>,
+    pub eliminated: Option<String>,
+}
+
+pub struct RunoffResult {
+    pub winner: Option<String>,
+    pub rounds: Vec<RunoffRound>,
+}
+
+//檢查排序票：每間候選餐廳都要排到，而且不能重複
+pub fn validate_ranking(candidates: &[Restaurant], ranking: &[String]) -> bool {
+    if ranking.is_empty() || ranking.len() != candidates.len() {
+        return false;
+    }
+    let mut seen = Vec::with_capacity(ranking.len());
+    for name in ranking {
+        if !candidates.iter().any(|c| &c.name == name) || seen.contains(&name) {
+            return false;
+        }
+        seen.push(name);
+    }
+    true
+}
+
+//認可投票的名單只能包含候選餐廳
+pub fn validate_approval(candidates: &[Restaurant], approvals: &HashSet<String>) -> bool {
+    approvals
+        .iter()
+        .all(|name| candidates.iter().any(|c| &c.name == name))
+}
+
+//認可投票：得到最多贊成的餐廳勝出，同票時較早加入的優先，沒有任何人贊成就沒有結果
+pub fn approval_winner(
+    candidates: &[String],
+    approvals: &[&HashSet<String>],
+) -> (Option<String>, Vec<CandidateCount>) {
+    let tally: Vec<CandidateCount> = candidates
+        .iter()
+        .map(|c| CandidateCount {
+            restaurant_name: c.clone(),
+            votes: approvals.iter().filter(|a| a.contains(c)).count(),
+        })
+        .collect();
+    let mut winner: Option<&CandidateCount> = None;
+    for count in &tally {
+        if count.votes > 0 && winner.is_none_or(|w| count.votes > w.votes) {
+            winner = Some(count);
+        }
+    }
+    (winner.map(|w| w.restaurant_name.clone()), tally)
+}
+
+//每間候選餐廳都要給分，0到N-1每個分數剛好用一次(等於一個完整的排名)
+pub fn validate_scores(candidates: &[Restaurant], scores: &HashMap<String, usize>) -> bool {
+    if scores.len() != candidates.len() || !candidates.iter().all(|c| scores.contains_key(&c.name)) {
+        return false;
+    }
+    let mut used = vec![false; candidates.len()];
+    scores.values().all(|score| {
+        *score < used.len() && !std::mem::replace(&mut used[*score], true)
+    })
+}
+
+#[derive(Serialize)]
+pub struct CandidateScore {
+    pub restaurant_name: String,
+    pub score: usize,
+}
+
+//波達計分：總分最高的勝出，同分時較早加入的優先；分數表依總分由高到低排列
+pub fn borda_winner(
+    candidates: &[String],
+    ballots: &[&HashMap<String, usize>],
+) -> (Option<String>, Vec<CandidateScore>) {
+    let mut table: Vec<CandidateScore> = candidates
+        .iter()
+        .map(|c| CandidateScore {
+            restaurant_name: c.clone(),
+            score: ballots.iter().filter_map(|b| b.get(c)).sum(),
+        })
+        .collect();
+    table.sort_by_key(|c| std::cmp::Reverse(c.score));
+    let winner = if ballots.is_empty() {
+        None
+    } else {
+        table.first().map(|c| c.restaurant_name.clone())
+    };
+    (winner, table)
+}
+
+//即時決選：每輪計算每張票上排最前面且尚未被淘汰的餐廳，
+//有人過半就勝出，否則淘汰票數最少的餐廳(同票時淘汰較晚加入的)再算下一輪
+pub fn instant_runoff(candidates: &[String], rankings: &[&Vec<String>]) -> RunoffResult {
+    let mut remaining: Vec<&String> = candidates.iter().collect();
+    let mut rounds = Vec::new();
+
+    while !remaining.is_empty() {
+        let mut counts: HashMap<&String, usize> = remaining.iter().map(|c| (*c, 0)).collect();
+        let mut active_ballots = 0;
+        for ranking in rankings {
+            if let Some(choice) = ranking.iter().find(|name| remaining.contains(name)) {
+                *counts.get_mut(choice).unwrap() += 1;
+                active_ballots += 1;
+            }
+        }
+        let tally: Vec<CandidateCount> = remaining
+            .iter()
+            .map(|c| CandidateCount {
+                restaurant_name: c.to_string(),
+                votes: counts[c],
+            })
+            .collect();
+
+        if active_ballots == 0 {
+            rounds.push(RunoffRound {
+                round: rounds.len() + 1,
+                tally,
+                eliminated: None,
+            });
+            return RunoffResult {
+                winner: None,
+                rounds,
+            };
+        }
+
+        let leader = remaining
+            .iter()
+            .copied()
+            .max_by_key(|c| counts[c])
+            .unwrap();
+        if counts[leader] * 2 > active_ballots || remaining.len() == 1 {
+            rounds.push(RunoffRound {
+                round: rounds.len() + 1,
+                tally,
+                eliminated: None,
+            });
+            return RunoffResult {
+                winner: Some(leader.to_string()),
+                rounds,
+            };
+        }
+
+        //min_by遇到同票會回傳第一個，所以反轉順序讓較晚加入的先被淘汰
+        let loser = remaining
+            .iter()
+            .rev()
+            .copied()
+            .min_by_key(|c| counts[c])
+            .unwrap();
+        rounds.push(RunoffRound {
+            round: rounds.len() + 1,
+            tally,
+            eliminated: Some(loser.to_string()),
+        });
+        remaining.retain(|c| *c != loser);
+    }
+
+    RunoffResult {
+        winner: None,
+        rounds,
+    }
+}
+
+#[derive(Serialize)]
+pub struct RouletteWeight {
+    pub restaurant_name: String,
+    pub weight: u64,
+}
+
+pub struct RouletteResult {
+    pub winner: String,
+    pub weights: Vec<RouletteWeight>,
+    //轉盤動畫依序經過的餐廳，最後一個就是抽中的
+    pub spins: Vec<String>,
+}
+
+//午餐轉盤：用ChaCha8Rng::seed_from_u64(seed)抽一次gen_range(0..總權重)，
+//落在哪間餐廳的累計權重區間就是哪間，公開seed之後任何人都能重算驗證
+pub fn roulette_draw(candidates: &[Restaurant], weighted: bool, seed: u64) -> RouletteResult {
+    let weights: Vec<RouletteWeight> = candidates
+        .iter()
+        .map(|c| RouletteWeight {
+            restaurant_name: c.name.clone(),
+            weight: if weighted { 1 + c.stars.len() as u64 } else { 1 },
+        })
+        .collect();
+    let total: u64 = weights.iter().map(|w| w.weight).sum();
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut ticket = rng.gen_range(0..total);
+    let mut winner_index = 0;
+    for (index, weight) in weights.iter().enumerate() {
+        if ticket < weight.weight {
+            winner_index = index;
+            break;
+        }
+        ticket -= weight.weight;
+    }
+
+    let spins = (0..ROULETTE_LAPS * candidates.len())
+        .map(|step| {
+            let index = (winner_index + step + 1) % candidates.len();
+            candidates[index].name.clone()
+        })
+        .collect();
+    RouletteResult {
+        winner: candidates[winner_index].name.clone(),
+        weights,
+        spins,
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct BracketMatch {
+    pub round: usize,
+    pub restaurants: Vec<String>,
+    pub votes: Vec<usize>,
+    //只有一間的話就是輪空直接晉級
+    pub bye: bool,
+    pub winner: String,
+}
+
+//淘汰賽：照加入順序兩兩對決，奇數時最後一間輪空，贏家進下一輪直到剩一間
+pub struct Bracket {
+    pub round: usize,
+    pub entrants: Vec<String>,
+    next: usize,
+    advancing: Vec<String>,
+    pub matches: Vec<BracketMatch>,
+    pub champion: Option<String>,
+}
+
+impl Bracket {
+    pub fn new(candidates: &[Restaurant]) -> Self {
+        let mut bracket = Bracket {
+            round: 1,
+            entrants: candidates.iter().map(|c| c.name.clone()).collect(),
+            next: 0,
+            advancing: Vec::new(),
+            matches: Vec::new(),
+            champion: None,
+        };
+        bracket.settle();
+        bracket
+    }
+
+    //目前要投票的對戰組合
+    pub fn current_match(&self) -> Option<(&String, &String)> {
+        if self.champion.is_some() || self.next + 1 >= self.entrants.len() {
+            return None;
+        }
+        Some((&self.entrants[self.next], &self.entrants[self.next + 1]))
+    }
+
+    //記錄這場的票數，同票由種子較前面的晉級
+    pub fn record_match(&mut self, first_votes: usize, second_votes: usize) {
+        let Some((first, second)) = self.current_match() else {
+            return;
+        };
+        let (first, second) = (first.clone(), second.clone());
+        let winner = if second_votes > first_votes { second.clone() } else { first.clone() };
+        self.matches.push(BracketMatch {
+            round: self.round,
+            restaurants: vec![first, second],
+            votes: vec![first_votes, second_votes],
+            bye: false,
+            winner: winner.clone(),
+        });
+        self.advancing.push(winner);
+        self.next += 2;
+        self.settle();
+    }
+
+    //處理輪空、換下一輪以及產生冠軍
+    fn settle(&mut self) {
+        loop {
+            if self.entrants.len() <= 1 {
+                self.champion = self.entrants.first().cloned();
+                return;
+            }
+            match self.entrants.len() - self.next {
+                0 => {
+                    self.entrants = std::mem::take(&mut self.advancing);
+                    self.next = 0;
+                    if self.entrants.len() > 1 {
+                        self.round += 1;
+                    }
+                }
+                1 => {
+                    let bye = self.entrants[self.next].clone();
+                    self.matches.push(BracketMatch {
+                        round: self.round,
+                        restaurants: vec![bye.clone()],
+                        votes: Vec::new(),
+                        bye: true,
+                        winner: bye.clone(),
+                    });
+                    self.advancing.push(bye);
+                    self.next += 1;
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct Strike {
+    pub user_name: String,
+    pub restaurant_name: String,
+}
+
+//否決淘汰：依順序輪流劃掉一間，剩最後一間就是結果
+pub struct Veto {
+    pub order: Vec<Uuid>,
+    pub turn: usize,
+    pub remaining: Vec<String>,
+    pub strikes: Vec<Strike>,
+}
+
+impl Veto {
+    pub fn new(candidates: &[Restaurant], order: Vec<Uuid>) -> Self {
+        Veto {
+            order,
+            turn: 0,
+            remaining: candidates.iter().map(|c| c.name.clone()).collect(),
+            strikes: Vec::new(),
+        }
+    }
+
+    pub fn current(&self) -> Option<Uuid> {
+        self.order.get(self.turn).copied()
+    }
+
+    //換下一位還在房間裡的人，跳過已經離開的
+    pub fn advance(&mut self, is_present: impl Fn(&Uuid) -> bool) {
+        for step in 1..=self.order.len() {
+            let turn = (self.turn + step) % self.order.len();
+            if is_present(&self.order[turn]) {
+                self.turn = turn;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn restaurants(names: &[&str]) -> Vec<Restaurant> {
+        names
+            .iter()
+            .map(|name| Restaurant {
+                name: name.to_string(),
+                remark: String::new(),
+                stars: HashSet::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn instant_runoff_eliminates_the_later_candidate_on_a_tie() {
+        let candidates = names(&["ramen", "sushi", "tacos"]);
+        let first = names(&["ramen", "sushi", "tacos"]);
+        let second = names(&["sushi", "ramen", "tacos"]);
+        let result = instant_runoff(&candidates, &[&first, &second]);
+        let eliminated: Vec<Option<String>> = result.rounds.iter().map(|r| r.eliminated.clone()).collect();
+        assert_eq!(eliminated, vec![Some("tacos".to_string()), Some("sushi".to_string()), None]);
+        assert_eq!(result.winner.as_deref(), Some("ramen"));
+    }
+
+    #[test]
+    fn instant_runoff_without_ballots_has_no_winner() {
+        let candidates = names(&["ramen", "sushi"]);
+        let result = instant_runoff(&candidates, &[]);
+        assert_eq!(result.winner, None);
+        assert_eq!(result.rounds.len(), 1);
+    }
+
+    #[test]
+    fn ranking_must_cover_every_candidate_once() {
+        let candidates = restaurants(&["ramen", "sushi", "tacos"]);
+        assert!(validate_ranking(&candidates, &names(&["tacos", "ramen", "sushi"])));
+        assert!(!validate_ranking(&candidates, &names(&["tacos", "ramen"])));
+        assert!(!validate_ranking(&candidates, &names(&["tacos", "tacos", "ramen"])));
+        assert!(!validate_ranking(&candidates, &names(&["tacos", "ramen", "pizza"])));
+    }
+
+    fn scores(pairs: &[(&str, usize)]) -> HashMap<String, usize> {
+        pairs.iter().map(|(name, score)| (name.to_string(), *score)).collect()
+    }
+
+    #[test]
+    fn scores_must_use_each_value_once() {
+        let candidates = restaurants(&["ramen", "sushi", "tacos"]);
+        assert!(validate_scores(&candidates, &scores(&[("ramen", 2), ("sushi", 0), ("tacos", 1)])));
+        assert!(!validate_scores(&candidates, &scores(&[("ramen", 2), ("sushi", 2), ("tacos", 1)])));
+    }
+
+    #[test]
+    fn scores_must_stay_below_the_candidate_count() {
+        let candidates = restaurants(&["ramen", "sushi", "tacos"]);
+        assert!(!validate_scores(&candidates, &scores(&[("ramen", 3), ("sushi", 0), ("tacos", 1)])));
+    }
+
+    #[test]
+    fn scores_must_cover_every_candidate() {
+        let candidates = restaurants(&["ramen", "sushi", "tacos"]);
+        assert!(!validate_scores(&candidates, &scores(&[("ramen", 1), ("sushi", 0)])));
+        assert!(!validate_scores(&candidates, &scores(&[("ramen", 2), ("sushi", 0), ("pizza", 1)])));
+    }
+
+    #[test]
+    fn borda_tie_goes_to_the_earlier_candidate() {
+        let candidates = names(&["ramen", "sushi", "tacos"]);
+        let first = scores(&[("ramen", 1), ("sushi", 2), ("tacos", 0)]);
+        let second = scores(&[("ramen", 2), ("sushi", 1), ("tacos", 0)]);
+        let (winner, table) = borda_winner(&candidates, &[&first, &second]);
+        assert_eq!(winner.as_deref(), Some("ramen"));
+        let order: Vec<&str> = table.iter().map(|c| c.restaurant_name.as_str()).collect();
+        assert_eq!(order, vec!["ramen", "sushi", "tacos"]);
+    }
+
+    #[test]
+    fn borda_without_ballots_has_no_winner() {
+        let candidates = names(&["ramen", "sushi"]);
+        let (winner, _) = borda_winner(&candidates, &[]);
+        assert_eq!(winner, None);
+    }
+
+    #[test]
+    fn bracket_gives_the_last_entrant_a_bye_on_odd_counts() {
+        let mut bracket = Bracket::new(&restaurants(&["ramen", "sushi", "tacos"]));
+        assert_eq!(bracket.current_match(), Some((&"ramen".to_string(), &"sushi".to_string())));
+        bracket.record_match(0, 1);
+        //tacos輪空，第二輪sushi對tacos
+        assert_eq!(bracket.round, 2);
+        assert_eq!(bracket.current_match(), Some((&"sushi".to_string(), &"tacos".to_string())));
+        //同票由種子較前面的晉級
+        bracket.record_match(2, 2);
+        assert_eq!(bracket.champion.as_deref(), Some("sushi"));
+        let byes: Vec<&BracketMatch> = bracket.matches.iter().filter(|m| m.bye).collect();
+        assert_eq!(byes.len(), 1);
+        assert_eq!(byes[0].winner, "tacos");
+    }
+
+    #[test]
+    fn bracket_with_five_entrants_takes_three_rounds() {
+        let mut bracket = Bracket::new(&restaurants(&["a", "b", "c", "d", "e"]));
+        while bracket.current_match().is_some() {
+            bracket.record_match(1, 0);
+        }
+        assert_eq!(bracket.champion.as_deref(), Some("a"));
+        assert_eq!(bracket.round, 3);
+        assert_eq!(bracket.matches.iter().filter(|m| m.bye).count(), 2);
+        assert_eq!(bracket.matches.iter().filter(|m| !m.bye).count(), 4);
+    }
+
+    #[test]
+    fn bracket_with_one_entrant_is_decided_immediately() {
+        let bracket = Bracket::new(&restaurants(&["ramen"]));
+        assert_eq!(bracket.champion.as_deref(), Some("ramen"));
+        assert!(bracket.current_match().is_none());
+        assert!(bracket.matches.is_empty());
+    }
+
+    #[test]
+    fn roulette_draw_is_reproducible_from_the_seed() {
+        let candidates = restaurants(&["ramen", "sushi", "tacos", "pizza"]);
+        let first = roulette_draw(&candidates, false, 42);
+        let second = roulette_draw(&candidates, false, 42);
+        assert_eq!(first.winner, second.winner);
+        assert_eq!(first.spins, second.spins);
+        //動畫停在抽中的那間
+        assert_eq!(first.spins.len(), ROULETTE_LAPS * candidates.len());
+        assert_eq!(first.spins.last(), Some(&first.winner));
+    }
+
+    #[test]
+    fn weighted_roulette_adds_one_share_per_star() {
+        let mut candidates = restaurants(&["ramen", "sushi"]);
+        candidates[1].stars.insert(Uuid::new_v4());
+        candidates[1].stars.insert(Uuid::new_v4());
+        let weights: Vec<u64> = roulette_draw(&candidates, true, 7).weights.iter().map(|w| w.weight).collect();
+        assert_eq!(weights, vec![1, 3]);
+        let weights: Vec<u64> = roulette_draw(&candidates, false, 7).weights.iter().map(|w| w.weight).collect();
+        assert_eq!(weights, vec![1, 1]);
+    }
+}
@@ -0,0 +1,14 @@
+//文字房間號碼用的單字表，挑唸出來不容易聽錯、拼法沒有爭議的字
+pub const ADJECTIVES: &[&str] = &[
+    "spicy", "crispy", "sweet", "salty", "smoky", "golden", "fluffy", "tangy",
+    "creamy", "zesty", "juicy", "toasty", "sticky", "chewy", "fresh", "hearty",
+    "minty", "peppery", "buttery", "lemony", "garlic", "honey", "roasted", "steamed",
+    "grilled", "fried", "baked", "sour", "savory", "cheesy", "frosty", "rustic",
+];
+
+pub const NOUNS: &[&str] = &[
+    "noodle", "dumpling", "taco", "burger", "pizza", "curry", "ramen", "waffle",
+    "pancake", "bagel", "burrito", "pretzel", "sushi", "mango", "lychee", "tofu",
+    "kimchi", "donut", "muffin", "omelet", "risotto", "falafel", "pickle", "pepper",
+    "onion", "potato", "tomato", "melon", "cookie", "biscuit", "sandwich", "salad",
+];
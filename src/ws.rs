@@ -1,5 +1,5 @@
 use crate::lobby::Lobby;
-use crate::messages::{ClientActorMessage, Connect, Disconnect, WsMessage,IncomingMessage, Type};
+use crate::messages::{ClientActorMessage, CloseConnection, Connect, Disconnect, JoinRoom, WsMessage,IncomingMessage, Type};
 use actix::ActorFutureExt;
 use actix::{fut, ActorContext, ContextFutureSpawner, WrapFuture};
 use actix::{Actor, Addr, Running, StreamHandler};
@@ -11,6 +11,14 @@ use serde_json::from_str;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+//連線後要在這段時間內送出join
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+//連線的狀態，報完名字才能在房間裡做事
+enum ConnState {
+    AwaitingJoin,
+    Joined,
+}
 //send：返回一个 Future，可以等待消息完成並獲取結果。
 //do_send：立即發送消息，不返回结果，不等待消息處理完成。
 pub struct WsConn {
@@ -19,16 +27,30 @@ pub struct WsConn {
     hb: Instant,
     id: Uuid,
     name:String,
+    spectator: bool,
+    reconnect_token: Option<String>,
+    host_key: Option<String>,
+    state: ConnState,
 }
 
 impl WsConn {
-    pub fn new(room: Uuid, lobby: Addr<Lobby>) -> WsConn {
+    pub fn new(
+        room: Uuid,
+        lobby: Addr<Lobby>,
+        spectator: bool,
+        reconnect_token: Option<String>,
+        host_key: Option<String>,
+    ) -> WsConn {
         WsConn {
             id: Uuid::new_v4(),
             room,
             hb: Instant::now(),
             lobby_addr: lobby,
             name:"".to_string(),
+            spectator,
+            reconnect_token,
+            host_key,
+            state: ConnState::AwaitingJoin,
         }
     }
 }
@@ -38,20 +60,30 @@ impl Actor for WsConn {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.hb(ctx);
+        self.join_timeout(ctx);
         //WsConn的ADDR讓Lobby知道要傳給誰
         let addr = ctx.address();
         self.lobby_addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close_addr: addr.recipient(),
                 lobby_id: self.room,
                 self_id: self.id,
+                spectator: self.spectator,
+                reconnect_token: self.reconnect_token.take(),
+                host_key: self.host_key.take(),
             })
             //轉換成actor
             .into_actor(self)
-            //處理轉換後的結果
-            .then(|res, _, ctx| {
+            //處理轉換後的結果，重新連線成功就換回原本的id跟名字
+            .then(|res, act, ctx| {
                 match res {
-                    Ok(_res) => (),
+                    Ok(Some(resumed)) => {
+                        act.id = resumed.id;
+                        act.name = resumed.name;
+                        act.state = ConnState::Joined;
+                    }
+                    Ok(None) => (),
                     _ => ctx.stop(),
                 }
                 fut::ready(())
@@ -64,7 +96,6 @@ impl Actor for WsConn {
         self.lobby_addr.do_send(Disconnect {
             id: self.id,
             room_id: self.room,
-            name: (*self.name).to_string(),
         });
         Running::Stop
     }
@@ -83,6 +114,32 @@ impl WsConn {
             ctx.ping(b"hi");
         });
     }
+
+    //時間到還沒join就斷線
+    fn join_timeout(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_later(JOIN_TIMEOUT, |act, ctx| {
+            if let ConnState::AwaitingJoin = act.state {
+                println!("Disconnecting join timeout");
+                act.send_error(ctx, "join timeout", "No join received in time.");
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("Join timed out.".to_string()),
+                }));
+                ctx.stop();
+            }
+        });
+    }
+
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, code: &str, message: &str) {
+        ctx.text(
+            serde_json::json!({
+                "type": "error",
+                "code": code,
+                "message": message,
+            })
+            .to_string(),
+        );
+    }
 }
 //處理從客戶端收到的訊息
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
@@ -108,17 +165,32 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                 // 解析json
                 let result: serde_json::Result<IncomingMessage> = from_str(&s);
                 match result {
+                    //還沒join就送其他訊息，不轉給Lobby
+                    Ok(parsed_msg)
+                        if matches!(self.state, ConnState::AwaitingJoin)
+                            && !matches!(parsed_msg, IncomingMessage::Join { .. }) =>
+                    {
+                        self.send_error(ctx, "not joined", "Send join before anything else.");
+                    }
                     Ok(parsed_msg) => {
                         match parsed_msg {
+                            //等Lobby檢查完名字再更新，名字不合格就維持原本的
                             IncomingMessage::Join { name } => {
-                                self.name = name;
-                                self.lobby_addr.do_send(ClientActorMessage {
-                                    r#type:Type::Join,
-                                    id: self.id,
-                                    msg: self.name.clone(),
-                                    room_id: self.room,
-                                    name: self.name.clone(),
-                                });
+                                self.lobby_addr
+                                    .send(JoinRoom {
+                                        id: self.id,
+                                        room_id: self.room,
+                                        name,
+                                    })
+                                    .into_actor(self)
+                                    .then(|res, act, _| {
+                                        if let Ok(Some(name)) = res {
+                                            act.name = name;
+                                            act.state = ConnState::Joined;
+                                        }
+                                        fut::ready(())
+                                    })
+                                    .wait(ctx);
                             }
                             IncomingMessage::Message { message } => {
                                 self.lobby_addr.do_send(ClientActorMessage {
@@ -130,7 +202,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                                 });
                             }
                             IncomingMessage::SetVoteMode { vote_mode } => {
-                                let _= self.lobby_addr.send(ClientActorMessage {
+                                self.lobby_addr.do_send(ClientActorMessage {
                                     r#type: Type::SetVoteMode(vote_mode),
                                     id: self.id,
                                     room_id: self.room,
@@ -166,10 +238,156 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                                     name: self.name.clone(),
                                 });
                             }
+                            IncomingMessage::RetractVote => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::RetractVote,
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::StartVote => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::StartVote,
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Rank { ranking } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Rank(ranking),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Approve { restaurant_names } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Approve(restaurant_names),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::SetVoteTimeout { seconds, missing_vote } => {
+                                let timeout = (seconds > 0).then(|| Duration::from_secs(seconds));
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::SetVoteTimeout(timeout, missing_vote),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::SetVoteLock { lock } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::SetVoteLock(lock),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::SetSecretBallot { secret_ballot } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::SetSecretBallot(secret_ballot),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Star { restaurant_name } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Star(restaurant_name),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Score { scores } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Score(scores),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Pick { restaurant_name } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Pick(restaurant_name),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Strike { restaurant_name } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Strike(restaurant_name),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Kick { name } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Kick(name),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::Mute { name, muted } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::Mute(name, muted),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::ForceCloseVote => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::ForceCloseVote,
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::TransferHost { name } => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::TransferHost(name),
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
+                            IncomingMessage::NewRound => {
+                                self.lobby_addr.do_send(ClientActorMessage {
+                                    r#type: Type::NewRound,
+                                    id: self.id,
+                                    msg: "".to_string(),
+                                    room_id: self.room,
+                                    name: self.name.clone(),
+                                });
+                            }
                         }
                     }
                     Err(e) => {
                         println!("Failed to parse JSON: {}", e);
+                        self.send_error(ctx, "invalid message", "Couldn't understand that message.");
                     }
                 }
             }
@@ -185,3 +403,15 @@ impl Handler<WsMessage> for WsConn {
         ctx.text(msg.0);
     }
 }
+
+impl Handler<CloseConnection> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseConnection, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}